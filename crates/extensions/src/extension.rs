@@ -1,17 +1,33 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
 
 use anyhow::{Result, anyhow};
+use bytes::Bytes;
 use nero_keyvalue_ttl::{KeyValueTTL, KeyValueTTLCtx, KeyValueTTLView};
+use nero_wasi_logging::{WasiLogging, WasiLoggingView};
 use semver::Version;
+use tokio::sync::Semaphore;
+use tracing::level_filters::LevelFilter;
 use wasm_metadata::Metadata;
 use wasmtime::{Store, component::Component};
-use wasmtime_wasi::{ResourceTable, WasiCtx, WasiCtxView, WasiView};
-use wasmtime_wasi_http::{WasiHttpCtx, WasiHttpView};
+use wasmtime_wasi::{ResourceTable, WasiCtx, WasiCtxView, WasiView, p2::pipe::MemoryInputPipe};
+use wasmtime_wasi_http::{
+    HttpResult, WasiHttpCtx, WasiHttpView,
+    bindings::http::types::ErrorCode,
+    body::HyperOutgoingBody,
+    types::{HostFutureIncomingResponse, OutgoingRequestConfig, default_send_request},
+};
 
 use crate::{
     Extension,
+    host::{EpochTicker, HostContext},
+    metrics::{CallMetrics, ExtensionMetrics},
     types::{EpisodesPage, FilterCategory, SearchFilter, Series, SeriesPage, Video},
-    wit::{ExtensionPre, since_v0_1_0_draft},
+    wit::{self, ExtensionPre, since_v0_1_0_draft, since_v0_2_0_draft},
 };
 
 pub struct WasmState {
@@ -19,19 +35,67 @@ pub struct WasmState {
     ctx: WasiCtx,
     http_ctx: WasiHttpCtx,
     keyvalue_ctx: Arc<KeyValueTTLCtx>,
+    log_level: LevelFilter,
+    outgoing_http: Arc<OutgoingHttpConfig>,
 }
 
 impl WasmState {
-    pub fn new(keyvalue_ctx: Arc<KeyValueTTLCtx>) -> Self {
+    /// Creates the state of a store, with `stdin` as the guest's standard input. Guest logs
+    /// below `log_level` are dropped and outgoing requests are checked against
+    /// `outgoing_http`.
+    pub fn new(
+        keyvalue_ctx: Arc<KeyValueTTLCtx>,
+        stdin: Bytes,
+        log_level: LevelFilter,
+        outgoing_http: Arc<OutgoingHttpConfig>,
+    ) -> Self {
+        let mut ctx = WasiCtx::builder();
+        if !stdin.is_empty() {
+            ctx.stdin(MemoryInputPipe::new(stdin));
+        }
+
         Self {
             table: ResourceTable::new(),
-            ctx: WasiCtx::builder().build(),
+            ctx: ctx.build(),
             http_ctx: WasiHttpCtx::new(),
             keyvalue_ctx,
+            log_level,
+            outgoing_http,
         }
     }
 }
 
+/// Limits on the HTTP requests extensions send themselves.
+#[derive(Debug, Clone, Default)]
+pub struct OutgoingHttpConfig {
+    /// Upper bound of the connect, first byte and between bytes timeouts of each request.
+    /// Guests may still ask for shorter ones.
+    pub timeout: Option<Duration>,
+    /// Hosts requests may be sent to, along with their subdomains. Any host when unset.
+    pub allowed_hosts: Option<HashSet<String>>,
+    /// Hosts requests are never sent to, along with their subdomains, even when allowed.
+    pub denied_hosts: HashSet<String>,
+}
+
+impl OutgoingHttpConfig {
+    fn allows(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        let matches = |pattern: &String| {
+            let pattern = pattern.to_ascii_lowercase();
+            host == pattern
+                || host
+                    .strip_suffix(&pattern)
+                    .is_some_and(|subdomain| subdomain.ends_with('.'))
+        };
+
+        !self.denied_hosts.iter().any(matches)
+            && self
+                .allowed_hosts
+                .as_ref()
+                .is_none_or(|allowed| allowed.iter().any(matches))
+    }
+}
+
 impl WasiView for WasmState {
     fn ctx(&mut self) -> wasmtime_wasi::WasiCtxView<'_> {
         WasiCtxView {
@@ -49,6 +113,32 @@ impl WasiHttpView for WasmState {
     fn table(&mut self) -> &mut ResourceTable {
         &mut self.table
     }
+
+    fn send_request(
+        &mut self,
+        request: http::Request<HyperOutgoingBody>,
+        mut config: OutgoingRequestConfig,
+    ) -> HttpResult<HostFutureIncomingResponse> {
+        let host = request.uri().host().unwrap_or_default();
+        if !self.outgoing_http.allows(host) {
+            tracing::warn!(host, "denied an extension request to a disallowed host");
+            return Err(ErrorCode::HttpRequestDenied.into());
+        }
+
+        if let Some(timeout) = self.outgoing_http.timeout {
+            config.connect_timeout = config.connect_timeout.min(timeout);
+            config.first_byte_timeout = config.first_byte_timeout.min(timeout);
+            config.between_bytes_timeout = config.between_bytes_timeout.min(timeout);
+        }
+
+        Ok(default_send_request(request, config))
+    }
+}
+
+impl WasiLoggingView for WasmState {
+    fn logging(&mut self) -> WasiLogging {
+        WasiLogging::new(self.log_level)
+    }
 }
 
 impl KeyValueTTLView for WasmState {
@@ -60,22 +150,79 @@ impl KeyValueTTLView for WasmState {
 pub struct ExtensionOptions {
     pub cache_dir: PathBuf,
     pub max_cache_size: Option<u64>,
+    /// Given to the extension as its standard input, e.g. a configuration blob. Empty by
+    /// default.
+    pub stdin: Bytes,
+    /// Maximum number of calls to the extension running at once, further calls wait for one
+    /// to finish. Unlimited when unset.
+    pub max_concurrent_calls: Option<usize>,
+    /// Number of instances kept warm between calls, skipping instantiation on reuse. Disabled
+    /// when zero, the default.
+    ///
+    /// A pooled instance keeps its whole store, guest memory and host resources alike, so
+    /// handles it cached such as stdio streams or key-value buckets stay valid. What the guest
+    /// keeps in memory is carried over to its next calls though, and standard input is only
+    /// read once per instance, so this is meant for extensions that don't depend on starting
+    /// from a clean state.
+    pub instance_pool_size: usize,
 }
 
 pub struct WasmExtension {
     extension_pre: ExtensionPre,
     metadata: Arc<Metadata>,
     keyvalue_ctx: Arc<KeyValueTTLCtx>,
+    stdin: Bytes,
+    metrics: ExtensionMetrics,
+    call_limit: Option<Semaphore>,
+    host_call_limit: Option<Arc<Semaphore>>,
+    log_level: LevelFilter,
+    call_timeout: Option<Duration>,
+    instance_pool: Mutex<Vec<(Store<WasmState>, wit::Extension)>>,
+    instance_pool_size: usize,
+    outgoing_http: Arc<OutgoingHttpConfig>,
+    /// Owned by the host, calls fail once it's gone since guest code only yields on epoch
+    /// changes.
+    epoch_ticker: Weak<EpochTicker>,
 }
 
+/// A call to an extension ran past the timeout set with [`crate::WasmHost::with_call_timeout`].
+#[derive(Debug)]
+pub struct CallTimedOut {
+    pub method: &'static str,
+    pub timeout: Duration,
+}
+
+impl std::fmt::Display for CallTimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "extension call to `{}` timed out after {:?}",
+            self.method, self.timeout
+        )
+    }
+}
+
+impl std::error::Error for CallTimedOut {}
+
 impl WasmExtension {
     pub(crate) async fn instantiate_async(
         version: Version,
         component: &Component,
         metadata: Metadata,
         options: ExtensionOptions,
+        host: HostContext,
     ) -> Result<Self> {
         let extension_pre = match version {
+            v if v >= *since_v0_2_0_draft::MIN_VER => {
+                let linker = since_v0_2_0_draft::linker(component.engine())?;
+                let pre = linker.instantiate_pre(component)?;
+                // Fails when the component leaves the optional exports out.
+                let optional = since_v0_2_0_draft::optional::OptionalExportsIndices::new(&pre).ok();
+                Ok(ExtensionPre::V0_2_0_DRAFT(
+                    since_v0_2_0_draft::ExtensionPre::new(pre)?,
+                    optional,
+                ))
+            }
             v if v >= *since_v0_1_0_draft::MIN_VER => {
                 let linker = since_v0_1_0_draft::linker(component.engine())?;
                 let pre = linker.instantiate_pre(component)?;
@@ -92,9 +239,121 @@ impl WasmExtension {
             extension_pre,
             metadata: Arc::new(metadata),
             keyvalue_ctx: Arc::new(kv_ctx),
+            stdin: options.stdin,
+            metrics: ExtensionMetrics::default(),
+            call_limit: options.max_concurrent_calls.map(Semaphore::new),
+            host_call_limit: host.call_limit,
+            log_level: host.log_level,
+            call_timeout: host.call_timeout,
+            instance_pool: Mutex::default(),
+            instance_pool_size: options.instance_pool_size,
+            outgoing_http: host.outgoing_http,
+            epoch_ticker: host.epoch_ticker,
         })
     }
 
+    /// Returns call counts, failures and timings of each extension method.
+    pub fn metrics(&self) -> HashMap<&'static str, CallMetrics> {
+        self.metrics.snapshot()
+    }
+
+    fn new_state(&self) -> WasmState {
+        WasmState::new(
+            self.keyvalue_ctx.clone(),
+            self.stdin.clone(),
+            self.log_level,
+            self.outgoing_http.clone(),
+        )
+    }
+
+    /// Takes a warm instance from the pool or instantiates the extension in a fresh store.
+    async fn instance(&self) -> Result<(Store<WasmState>, wit::Extension)> {
+        let pooled = self.instance_pool.lock().unwrap().pop();
+        if let Some((mut store, extension)) = pooled {
+            // The host state is left as is, the guest may hold handles into its resource
+            // table.
+            store.epoch_deadline_async_yield_and_update(1);
+            return Ok((store, extension));
+        }
+
+        let mut store = Store::new(self.extension_pre.engine(), self.new_state());
+        store.epoch_deadline_async_yield_and_update(1);
+        let extension = self.extension_pre.instantiate_async(&mut store).await?;
+        Ok((store, extension))
+    }
+
+    /// Runs `call` against an instance of the extension, recording how long instantiation and
+    /// guest execution took. Instances are returned to the pool after successful calls only,
+    /// a trap may have left one unusable.
+    ///
+    /// Calls over the extension's or the host's concurrency limit wait for a slot first. They
+    /// fail once the host that loaded the extension is dropped.
+    async fn call<T, F>(&self, method: &'static str, call: F) -> Result<T>
+    where
+        F: AsyncFnOnce(&mut Store<WasmState>, &wit::Extension) -> Result<T>,
+    {
+        // Held for the whole call, so dropping the host meanwhile doesn't stop the epoch under it.
+        let Some(_epoch_ticker) = self.epoch_ticker.upgrade() else {
+            anyhow::bail!("the host that loaded the extension was dropped");
+        };
+
+        // The extension's own slot comes first, so calls queued behind a busy extension don't
+        // hold on to host-wide slots other extensions could use.
+        let _permit = match &self.call_limit {
+            Some(limit) => Some(limit.acquire().await?),
+            None => None,
+        };
+        let _host_permit = match &self.host_call_limit {
+            Some(limit) => Some(limit.acquire().await?),
+            None => None,
+        };
+
+        let started = Instant::now();
+        let run = async {
+            let (mut store, extension) = match self.instance().await {
+                Ok(instance) => instance,
+                Err(err) => {
+                    self.metrics
+                        .record(method, started.elapsed(), Duration::ZERO, false);
+                    return Err(err);
+                }
+            };
+            let instantiation_time = started.elapsed();
+
+            let executed = Instant::now();
+            let result = call(&mut store, &extension).await;
+            self.metrics.record(
+                method,
+                instantiation_time,
+                executed.elapsed(),
+                result.is_ok(),
+            );
+
+            if result.is_ok() {
+                let mut pool = self.instance_pool.lock().unwrap();
+                if pool.len() < self.instance_pool_size {
+                    pool.push((store, extension));
+                }
+            }
+
+            result
+        };
+
+        let Some(timeout) = self.call_timeout else {
+            return run.await;
+        };
+        // Guest code yields on every epoch, so dropping the call on timeout stops it even when
+        // it never awaits anything.
+        match tokio::time::timeout(timeout, run).await {
+            Ok(result) => result,
+            Err(_) => {
+                self.metrics
+                    .record(method, started.elapsed(), Duration::ZERO, false);
+                Err(CallTimedOut { method, timeout }.into())
+            }
+        }
+    }
+
     pub(crate) fn get_version(wasm_bytes: &[u8]) -> Result<Version> {
         const PACKAGE_NAMESPACE: &str = "nero";
         const PACKAGE_NAME: &str = "extension";
@@ -123,13 +382,10 @@ impl Extension for WasmExtension {
     }
 
     async fn filters(&self) -> Result<Vec<FilterCategory>> {
-        let mut store = Store::new(
-            self.extension_pre.engine(),
-            WasmState::new(self.keyvalue_ctx.clone()),
-        );
-
-        let extension = self.extension_pre.instantiate_async(&mut store).await?;
-        extension.filters(store).await
+        self.call("filters", async |store, extension| {
+            extension.filters(store).await
+        })
+        .await
     }
 
     async fn search(
@@ -138,23 +394,17 @@ impl Extension for WasmExtension {
         page: Option<u16>,
         filters: Vec<SearchFilter>,
     ) -> Result<SeriesPage> {
-        let mut store = Store::new(
-            self.extension_pre.engine(),
-            WasmState::new(self.keyvalue_ctx.clone()),
-        );
-
-        let extension = self.extension_pre.instantiate_async(&mut store).await?;
-        extension.search(store, query, page, filters).await
+        self.call("search", async |store, extension| {
+            extension.search(store, query, page, filters).await
+        })
+        .await
     }
 
     async fn get_series_info(&self, series_id: &str) -> Result<Series> {
-        let mut store = Store::new(
-            self.extension_pre.engine(),
-            WasmState::new(self.keyvalue_ctx.clone()),
-        );
-
-        let extension = self.extension_pre.instantiate_async(&mut store).await?;
-        extension.get_series_info(store, series_id).await
+        self.call("get_series_info", async |store, extension| {
+            extension.get_series_info(store, series_id).await
+        })
+        .await
     }
 
     async fn get_series_episodes(
@@ -162,24 +412,32 @@ impl Extension for WasmExtension {
         series_id: &str,
         page: Option<u16>,
     ) -> Result<EpisodesPage> {
-        let mut store = Store::new(
-            self.extension_pre.engine(),
-            WasmState::new(self.keyvalue_ctx.clone()),
-        );
-
-        let extension = self.extension_pre.instantiate_async(&mut store).await?;
-        extension.get_series_episodes(store, series_id, page).await
+        self.call("get_series_episodes", async |store, extension| {
+            extension.get_series_episodes(store, series_id, page).await
+        })
+        .await
     }
 
     async fn get_series_videos(&self, series_id: &str, episode_id: &str) -> Result<Vec<Video>> {
-        let mut store = Store::new(
-            self.extension_pre.engine(),
-            WasmState::new(self.keyvalue_ctx.clone()),
-        );
+        self.call("get_series_videos", async |store, extension| {
+            extension
+                .get_series_videos(store, series_id, episode_id)
+                .await
+        })
+        .await
+    }
 
-        let extension = self.extension_pre.instantiate_async(&mut store).await?;
-        extension
-            .get_series_videos(store, series_id, episode_id)
-            .await
+    async fn get_video(
+        &self,
+        series_id: &str,
+        episode_id: &str,
+        video_id: &str,
+    ) -> Result<Option<Video>> {
+        self.call("get_video", async |store, extension| {
+            extension
+                .get_video(store, series_id, episode_id, video_id)
+                .await
+        })
+        .await
     }
 }
@@ -1,45 +1,186 @@
-use std::path::Path;
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock, Weak},
+    time::Duration,
+};
 
+use tokio::{sync::Semaphore, task::JoinHandle};
+use tracing::level_filters::LevelFilter;
 use wasm_metadata::Payload;
-use wasmtime::{Engine, component::Component};
+use wasmtime::{Cache, CacheConfig, Engine, component::Component};
 
-use crate::extension::{ExtensionOptions, WasmExtension};
+use crate::extension::{ExtensionOptions, OutgoingHttpConfig, WasmExtension};
+
+/// How often the engine epoch advances unless configured otherwise.
+pub const DEFAULT_EPOCH_INTERVAL: Duration = Duration::from_millis(10);
 
 pub struct WasmHost {
     engine: Engine,
+    epoch_interval: Duration,
+    epoch_ticker: OnceLock<Arc<EpochTicker>>,
+    call_limit: Option<Arc<Semaphore>>,
+    log_level: LevelFilter,
+    call_timeout: Option<Duration>,
+    outgoing_http: Arc<OutgoingHttpConfig>,
 }
 
 impl Default for WasmHost {
     fn default() -> Self {
         Self {
-            engine: {
-                let mut config = wasmtime::Config::new();
-                config.async_support(true);
-                config.wasm_component_model(true);
-                wasmtime::Engine::new(&config).unwrap()
-            },
+            engine: new_engine(None).unwrap(),
+            epoch_interval: DEFAULT_EPOCH_INTERVAL,
+            epoch_ticker: OnceLock::new(),
+            call_limit: None,
+            log_level: LevelFilter::TRACE,
+            call_timeout: None,
+            outgoing_http: Arc::default(),
         }
     }
 }
 
+fn new_engine(cache_dir: Option<PathBuf>) -> wasmtime::Result<Engine> {
+    let mut config = wasmtime::Config::new();
+    config.async_support(true);
+    config.wasm_component_model(true);
+    config.epoch_interruption(true);
+
+    if let Some(dir) = cache_dir {
+        let mut cache_config = CacheConfig::new();
+        cache_config.with_directory(dir);
+        config.cache(Some(Cache::new(cache_config)?));
+    }
+
+    Engine::new(&config)
+}
+
+/// Advances the engine epoch until dropped. Owned by the host, extensions only hold on to it
+/// while a call runs, so it stops once the host is dropped and no call is left.
+pub(crate) struct EpochTicker(JoinHandle<()>);
+
+impl Drop for EpochTicker {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// What an extension takes from the host that loaded it.
+pub(crate) struct HostContext {
+    pub(crate) call_limit: Option<Arc<Semaphore>>,
+    pub(crate) log_level: LevelFilter,
+    pub(crate) call_timeout: Option<Duration>,
+    pub(crate) outgoing_http: Arc<OutgoingHttpConfig>,
+    pub(crate) epoch_ticker: Weak<EpochTicker>,
+}
+
 impl WasmHost {
+    /// Sets how often the epoch of the host's engine advances. Guest code yields back to the
+    /// async runtime on every epoch, so this bounds how long a call can run uninterrupted.
+    pub fn with_epoch_interval(mut self, interval: Duration) -> Self {
+        self.epoch_interval = interval;
+        self
+    }
+
+    /// Limits how many calls run at once across every extension loaded afterwards, bounding
+    /// wasm instantiation and outbound traffic. Further calls wait for one to finish.
+    pub fn with_max_concurrent_calls(mut self, limit: usize) -> Self {
+        self.call_limit = Some(Arc::new(Semaphore::new(limit)));
+        self
+    }
+
+    /// Fails calls to extensions loaded afterwards with a [`crate::CallTimedOut`] once they run
+    /// longer than `timeout`, instantiation included. Calls may run indefinitely by default.
+    ///
+    /// The timeout is only checked when the guest yields, which it does on every epoch, so a
+    /// hung call is stopped within one epoch interval of its deadline.
+    pub fn with_call_timeout(mut self, timeout: Duration) -> Self {
+        self.call_timeout = Some(timeout);
+        self
+    }
+
+    /// Restricts the hosts extensions loaded afterwards may send requests to and caps their
+    /// timeouts. Denied requests fail in the guest with `HTTP-request-denied`.
+    pub fn with_outgoing_http(mut self, config: OutgoingHttpConfig) -> Self {
+        self.outgoing_http = Arc::new(config);
+        self
+    }
+
+    /// Drops logs of extensions loaded afterwards below `level` before they reach `tracing`.
+    /// Every log is forwarded by default, leaving filtering to the subscriber.
+    pub fn with_log_level(mut self, level: LevelFilter) -> Self {
+        self.log_level = level;
+        self
+    }
+
+    /// Keeps compiled extensions in `dir` and reuses them on later loads, across extensions
+    /// and runs.
+    ///
+    /// Compiling a component is the bulk of loading it, so with several extensions installed
+    /// this cuts startup time to little more than reading the cached artifacts. Entries are
+    /// keyed by the component bytes and engine settings, a changed extension or wasmtime
+    /// upgrade simply compiles again.
+    ///
+    /// Must be set before any extension is loaded.
+    pub fn with_compilation_cache(mut self, dir: impl Into<PathBuf>) -> wasmtime::Result<Self> {
+        self.engine = new_engine(Some(dir.into()))?;
+        Ok(self)
+    }
+
+    /// Starts the epoch ticker shared by every store created from this host's engine, once.
+    fn epoch_ticker(&self) -> Weak<EpochTicker> {
+        let ticker = self.epoch_ticker.get_or_init(|| {
+            let engine = self.engine.clone();
+            let period = self.epoch_interval;
+            Arc::new(EpochTicker(tokio::spawn(async move {
+                let mut interval = tokio::time::interval(period);
+                loop {
+                    interval.tick().await;
+                    engine.increment_epoch();
+                }
+            })))
+        });
+        Arc::downgrade(ticker)
+    }
+
     pub async fn load_extension_async<P: AsRef<Path>>(
         &self,
         path: P,
         options: ExtensionOptions,
     ) -> wasmtime::Result<WasmExtension> {
-        let path = path.as_ref();
+        let wasm_bytes = tokio::fs::read(path).await?;
+        self.load_extension_from_bytes_async(&wasm_bytes, options)
+            .await
+    }
+
+    /// Loads an extension from its component bytes, e.g. downloaded in memory or embedded in
+    /// the binary.
+    pub async fn load_extension_from_bytes_async(
+        &self,
+        wasm_bytes: &[u8],
+        options: ExtensionOptions,
+    ) -> wasmtime::Result<WasmExtension> {
+        let epoch_ticker = self.epoch_ticker();
 
-        let wasm_bytes = std::fs::read(path)?;
-        let version = WasmExtension::get_version(&wasm_bytes)?;
-        let component = Component::from_file(&self.engine, path)?;
-        let metadata = match Payload::from_binary(&wasm_bytes)? {
+        let version = WasmExtension::get_version(wasm_bytes)?;
+        let component = Component::from_binary(&self.engine, wasm_bytes)?;
+        let metadata = match Payload::from_binary(wasm_bytes)? {
             Payload::Component { metadata, .. } => metadata,
             Payload::Module(..) => unreachable!(),
         };
 
-        let extension =
-            WasmExtension::instantiate_async(version, &component, metadata, options).await?;
+        let extension = WasmExtension::instantiate_async(
+            version,
+            &component,
+            metadata,
+            options,
+            HostContext {
+                call_limit: self.call_limit.clone(),
+                log_level: self.log_level,
+                call_timeout: self.call_timeout,
+                outgoing_http: self.outgoing_http.clone(),
+                epoch_ticker,
+            },
+        )
+        .await?;
 
         Ok(extension)
     }
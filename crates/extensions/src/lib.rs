@@ -1,12 +1,14 @@
 mod extension;
 mod host;
+mod metrics;
 pub mod types;
 mod wit;
 
 use std::sync::Arc;
 
-pub use extension::{ExtensionOptions, WasmExtension};
-pub use host::WasmHost;
+pub use extension::{CallTimedOut, ExtensionOptions, OutgoingHttpConfig, WasmExtension};
+pub use host::{DEFAULT_EPOCH_INTERVAL, WasmHost};
+pub use metrics::{CallMetrics, encode_prometheus};
 
 use anyhow::Result;
 use wasm_metadata::Metadata;
@@ -42,4 +44,14 @@ pub trait Extension {
         series_id: &str,
         episode_id: &str,
     ) -> impl std::future::Future<Output = Result<Vec<Video>>>;
+
+    /// Resolves a single video of an episode again by its id, e.g. after its link expired.
+    /// `None` when the episode no longer has it. Fails for extensions built against
+    /// 0.1.0-draft, whose videos have no id.
+    fn get_video(
+        &self,
+        series_id: &str,
+        episode_id: &str,
+        video_id: &str,
+    ) -> impl std::future::Future<Output = Result<Option<Video>>>;
 }
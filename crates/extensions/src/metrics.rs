@@ -0,0 +1,99 @@
+use std::{collections::HashMap, fmt::Write, sync::Mutex, time::Duration};
+
+/// Aggregated timings and outcomes of the calls made to one extension method.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CallMetrics {
+    pub calls: u64,
+    pub failures: u64,
+    /// Time spent creating the store and instantiating the component.
+    pub instantiation_time: Duration,
+    /// Time spent running guest code.
+    pub execution_time: Duration,
+}
+
+#[derive(Default)]
+pub(crate) struct ExtensionMetrics {
+    methods: Mutex<HashMap<&'static str, CallMetrics>>,
+}
+
+impl ExtensionMetrics {
+    pub(crate) fn record(
+        &self,
+        method: &'static str,
+        instantiation_time: Duration,
+        execution_time: Duration,
+        success: bool,
+    ) {
+        let mut methods = self.methods.lock().unwrap();
+        let metrics = methods.entry(method).or_default();
+        metrics.calls += 1;
+        if !success {
+            metrics.failures += 1;
+        }
+        metrics.instantiation_time += instantiation_time;
+        metrics.execution_time += execution_time;
+    }
+
+    /// Returns the metrics recorded so far, keyed by method name.
+    pub(crate) fn snapshot(&self) -> HashMap<&'static str, CallMetrics> {
+        self.methods.lock().unwrap().clone()
+    }
+}
+
+/// Encodes call metrics in the Prometheus text format, labelled by extension and method.
+/// `extensions` pairs each extension's name with its [`crate::WasmExtension::metrics`].
+pub fn encode_prometheus<'a>(
+    extensions: impl IntoIterator<Item = (&'a str, HashMap<&'static str, CallMetrics>)>,
+) -> String {
+    let extensions = extensions.into_iter().collect::<Vec<_>>();
+    let families: [(&str, &str, &str, fn(&CallMetrics) -> f64); 4] = [
+        (
+            "nero_extension_calls_total",
+            "counter",
+            "Calls made to an extension method.",
+            |m| m.calls as f64,
+        ),
+        (
+            "nero_extension_call_failures_total",
+            "counter",
+            "Calls to an extension method that failed.",
+            |m| m.failures as f64,
+        ),
+        (
+            "nero_extension_instantiation_seconds_total",
+            "counter",
+            "Time spent instantiating an extension for its method calls.",
+            |m| m.instantiation_time.as_secs_f64(),
+        ),
+        (
+            "nero_extension_execution_seconds_total",
+            "counter",
+            "Time spent running guest code of an extension method.",
+            |m| m.execution_time.as_secs_f64(),
+        ),
+    ];
+
+    let mut out = String::new();
+    for (name, kind, help, value) in families {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        for (extension, methods) in &extensions {
+            for (method, metrics) in methods {
+                let _ = writeln!(
+                    out,
+                    "{name}{{extension=\"{}\",method=\"{method}\"}} {}",
+                    escape_label(extension),
+                    value(metrics)
+                );
+            }
+        }
+    }
+    out
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
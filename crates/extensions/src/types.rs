@@ -3,13 +3,20 @@ use bytes::Bytes;
 pub type HttpRequest = http::Request<Option<Bytes>>;
 
 pub enum MediaResource {
-    HttpRequest(Box<HttpRequest>),
+    HttpRequest {
+        request: Box<HttpRequest>,
+        /// MIME type declared by the extension, not validated.
+        mime_hint: Option<String>,
+    },
     MagnetUri(String),
 }
 
 pub struct Page<T> {
     pub items: Vec<T>,
     pub has_next_page: bool,
+    pub current_page: Option<u16>,
+    pub total_pages: Option<u16>,
+    pub total_items: Option<u32>,
 }
 
 pub type SeriesPage = Page<Series>;
@@ -29,14 +36,19 @@ pub struct Episode {
     pub title: Option<String>,
     pub thumbnail_resource: Option<MediaResource>,
     pub description: Option<String>,
+    pub next_episode_id: Option<String>,
 }
 
 type Resolution = (u16, u16);
 
 pub struct Video {
+    /// Identifies the video among the ones of its episode. Extensions built against
+    /// 0.1.0-draft don't provide one.
+    pub id: Option<String>,
     pub media_resource: MediaResource,
     pub server: String,
     pub resolution: Resolution,
+    pub next_episode_id: Option<String>,
 }
 
 pub struct Filter {
@@ -12,6 +12,7 @@ use crate::{
 };
 
 pub mod since_v0_1_0_draft;
+pub mod since_v0_2_0_draft;
 
 pub(super) trait AsyncTryFromWithStore<T>: Sized {
     async fn try_from_with_store(value: T, store: &mut Store<WasmState>) -> Result<Self>;
@@ -84,12 +85,17 @@ impl IntoHttpRequest for HostOutgoingRequest {
 #[allow(non_camel_case_types)]
 pub enum ExtensionPre {
     V0_1_0_DRAFT(since_v0_1_0_draft::ExtensionPre<WasmState>),
+    V0_2_0_DRAFT(
+        since_v0_2_0_draft::ExtensionPre<WasmState>,
+        Option<since_v0_2_0_draft::optional::OptionalExportsIndices>,
+    ),
 }
 
 impl ExtensionPre {
     pub fn engine(&self) -> &Engine {
         match self {
             ExtensionPre::V0_1_0_DRAFT(extension_pre) => extension_pre.engine(),
+            ExtensionPre::V0_2_0_DRAFT(extension_pre, _) => extension_pre.engine(),
         }
     }
 
@@ -99,6 +105,15 @@ impl ExtensionPre {
                 let extension = pre.instantiate_async(store).await?;
                 Ok(Extension::V0_1_0_DRAFT(extension))
             }
+            ExtensionPre::V0_2_0_DRAFT(pre, optional) => {
+                let instance = pre.instance_pre().instantiate_async(&mut *store).await?;
+                let extension = since_v0_2_0_draft::Extension::new(&mut *store, &instance)?;
+                let optional = optional
+                    .as_ref()
+                    .map(|indices| indices.load(&mut *store, &instance))
+                    .transpose()?;
+                Ok(Extension::V0_2_0_DRAFT(extension, optional))
+            }
         }
     }
 }
@@ -106,105 +121,147 @@ impl ExtensionPre {
 #[allow(non_camel_case_types)]
 pub enum Extension {
     V0_1_0_DRAFT(since_v0_1_0_draft::Extension),
+    V0_2_0_DRAFT(
+        since_v0_2_0_draft::Extension,
+        Option<since_v0_2_0_draft::optional::OptionalExports>,
+    ),
+}
+
+/// Evaluates `$body` with `$extractor` bound to the exported extractor of `$extension`,
+/// whichever version of the world it implements. The body is expanded once per version, so the
+/// conversions it uses resolve to that version's types.
+macro_rules! with_extractor {
+    ($extension:expr, |$extractor:ident| $body:expr) => {
+        match $extension {
+            Extension::V0_1_0_DRAFT(extension) => {
+                let $extractor = extension.nero_extension_extractor();
+                $body
+            }
+            Extension::V0_2_0_DRAFT(extension, _) => {
+                let $extractor = extension.nero_extension_extractor();
+                $body
+            }
+        }
+    };
 }
 
 impl Extension {
     pub async fn filters(
         &self,
-        mut store: wasmtime::Store<WasmState>,
+        store: &mut wasmtime::Store<WasmState>,
     ) -> Result<Vec<FilterCategory>> {
-        match self {
-            Extension::V0_1_0_DRAFT(extension) => {
-                let res = extension
-                    .nero_extension_extractor()
-                    .call_filters(&mut store)
-                    .await?
-                    .map_err(|err| anyhow!("{err}"))?;
+        with_extractor!(self, |extractor| {
+            let res = extractor
+                .call_filters(&mut *store)
+                .await?
+                .map_err(|err| anyhow!("{err}"))?;
 
-                Ok(res.into_iter().map(Into::into).collect())
-            }
-        }
+            Ok(res.into_iter().map(Into::into).collect())
+        })
     }
 
     pub async fn search(
         &self,
-        mut store: wasmtime::Store<WasmState>,
+        store: &mut wasmtime::Store<WasmState>,
         query: &str,
         page: Option<u16>,
         filters: Vec<SearchFilter>,
     ) -> Result<SeriesPage> {
-        match self {
-            Extension::V0_1_0_DRAFT(extension) => {
-                let filters = filters.into_iter().map(Into::into).collect::<Vec<_>>();
+        with_extractor!(self, |extractor| {
+            let filters = filters.into_iter().map(Into::into).collect::<Vec<_>>();
 
-                let res = extension
-                    .nero_extension_extractor()
-                    .call_search(&mut store, query, page, &filters)
-                    .await?
-                    .map_err(|err| anyhow!("{err}"))?;
+            let res = extractor
+                .call_search(&mut *store, query, page, &filters)
+                .await?
+                .map_err(|err| anyhow!("{err}"))?;
 
-                res.try_into_with_store(&mut store).await
-            }
-        }
+            res.try_into_with_store(store).await
+        })
     }
 
     pub async fn get_series_info(
         &self,
-        mut store: wasmtime::Store<WasmState>,
+        store: &mut wasmtime::Store<WasmState>,
         series_id: &str,
     ) -> Result<Series> {
-        match self {
-            Extension::V0_1_0_DRAFT(extension) => {
-                let res = extension
-                    .nero_extension_extractor()
-                    .call_get_series_info(&mut store, series_id)
-                    .await?
-                    .map_err(|err| anyhow!("{err}"))?;
+        with_extractor!(self, |extractor| {
+            let res = extractor
+                .call_get_series_info(&mut *store, series_id)
+                .await?
+                .map_err(|err| anyhow!("{err}"))?;
 
-                res.try_into_with_store(&mut store).await
-            }
-        }
+            res.try_into_with_store(store).await
+        })
     }
 
     pub async fn get_series_episodes(
         &self,
-        mut store: wasmtime::Store<WasmState>,
+        store: &mut wasmtime::Store<WasmState>,
         series_id: &str,
         page: Option<u16>,
     ) -> Result<EpisodesPage> {
-        match self {
-            Extension::V0_1_0_DRAFT(extension) => {
-                let res = extension
-                    .nero_extension_extractor()
-                    .call_get_series_episodes(&mut store, series_id, page)
-                    .await?
-                    .map_err(|err| anyhow!("{err}"))?;
+        with_extractor!(self, |extractor| {
+            let res = extractor
+                .call_get_series_episodes(&mut *store, series_id, page)
+                .await?
+                .map_err(|err| anyhow!("{err}"))?;
 
-                res.try_into_with_store(&mut store).await
-            }
-        }
+            res.try_into_with_store(store).await
+        })
     }
 
     pub async fn get_series_videos(
         &self,
-        mut store: wasmtime::Store<WasmState>,
+        store: &mut wasmtime::Store<WasmState>,
         series_id: &str,
         episode_id: &str,
     ) -> Result<Vec<Video>> {
-        match self {
-            Extension::V0_1_0_DRAFT(extension) => {
-                let res = extension
-                    .nero_extension_extractor()
-                    .call_get_series_videos(&mut store, series_id, episode_id)
-                    .await?
-                    .map_err(|err| anyhow!("{err}"))?;
-
-                let mut items = Vec::new();
-                for video in res {
-                    items.push(video.try_into_with_store(&mut store).await?);
-                }
-                Ok(items)
+        with_extractor!(self, |extractor| {
+            let res = extractor
+                .call_get_series_videos(&mut *store, series_id, episode_id)
+                .await?
+                .map_err(|err| anyhow!("{err}"))?;
+
+            let mut items = Vec::new();
+            for video in res {
+                items.push(video.try_into_with_store(store).await?);
             }
+            Ok(items)
+        })
+    }
+
+    /// Resolves a single video of an episode again, through the `get-video` export when the
+    /// extension has it. Videos of 0.1.0-draft extensions have no id, so it fails for them.
+    pub async fn get_video(
+        &self,
+        store: &mut wasmtime::Store<WasmState>,
+        series_id: &str,
+        episode_id: &str,
+        video_id: &str,
+    ) -> Result<Option<Video>> {
+        if let Extension::V0_1_0_DRAFT(_) = self {
+            return Err(anyhow!(
+                "get-video is unsupported by extensions built against 0.1.0-draft"
+            ));
         }
+
+        if let Extension::V0_2_0_DRAFT(_, Some(optional)) = self {
+            let res = optional
+                .nero_extension_video_resolver()
+                .call_get_video(&mut *store, series_id, episode_id, video_id)
+                .await?
+                .map_err(|err| anyhow!("{err}"))?;
+
+            return match res {
+                Some(video) => Ok(Some(video.try_into_with_store(store).await?)),
+                None => Ok(None),
+            };
+        }
+
+        // Without the optional export, the video is picked out of every video of the episode.
+        let videos = self.get_series_videos(store, series_id, episode_id).await?;
+        Ok(videos
+            .into_iter()
+            .find(|video| video.id.as_deref() == Some(video_id)))
     }
 }
@@ -91,6 +91,9 @@ impl AsyncTryFromWithStore<SeriesPage> for crate::types::SeriesPage {
         Ok(crate::types::SeriesPage {
             items,
             has_next_page: page.has_next_page,
+            current_page: None,
+            total_pages: None,
+            total_items: None,
         })
     }
 }
@@ -125,6 +128,9 @@ impl AsyncTryFromWithStore<EpisodesPage> for crate::types::EpisodesPage {
         Ok(crate::types::EpisodesPage {
             items,
             has_next_page: page.has_next_page,
+            current_page: None,
+            total_pages: None,
+            total_items: None,
         })
     }
 }
@@ -143,6 +149,7 @@ impl AsyncTryFromWithStore<Episode> for crate::types::Episode {
                 None => None,
             },
             description: episode.description,
+            next_episode_id: None,
         })
     }
 }
@@ -153,9 +160,11 @@ impl AsyncTryFromWithStore<Video> for crate::types::Video {
         store: &mut wasmtime::Store<WasmState>,
     ) -> anyhow::Result<Self> {
         Ok(crate::types::Video {
+            id: None,
             media_resource: video.media_resource.try_into_with_store(store).await?,
             server: video.server,
             resolution: video.resolution,
+            next_episode_id: None,
         })
     }
 }
@@ -180,7 +189,10 @@ impl AsyncTryFromWithStore<MediaResource> for crate::types::MediaResource {
             MediaResource::HttpRequest(resource) => {
                 let outgoing: HostOutgoingRequest = resource.try_into_with_store(store).await?;
                 let request = outgoing.into_http_request().await?;
-                Ok(crate::types::MediaResource::HttpRequest(Box::new(request)))
+                Ok(crate::types::MediaResource::HttpRequest {
+                    request: Box::new(request),
+                    mime_hint: None,
+                })
             }
             MediaResource::MagnetUri(uri) => {
                 MagnetURI::from_str(&uri)
@@ -0,0 +1,216 @@
+use std::{str::FromStr, sync::LazyLock};
+
+use self::nero::extension::types::{
+    Episode, EpisodesPage, Filter, FilterCategory, SearchFilter, Series, SeriesPage, Video,
+};
+
+use anyhow::Result;
+use magnet_uri::MagnetURI;
+use semver::Version;
+use wasmtime::{
+    Engine,
+    component::{Linker, bindgen},
+};
+use wasmtime_wasi_http::types::HostOutgoingRequest;
+
+use crate::{
+    AsyncTryIntoWithStore,
+    extension::WasmState,
+    wit::{
+        AsyncTryFromWithStore, IntoHttpRequest,
+        since_v0_2_0_draft::nero::extension::types::MediaResource,
+    },
+};
+
+pub static MIN_VER: LazyLock<Version> =
+    LazyLock::new(|| Version::parse("0.2.0-draft").expect("invalid version"));
+
+bindgen!({
+    path: "./wit/v0.2.0-draft",
+    world: "nero:extension/extension",
+    imports: { default: async | trappable, },
+    exports: { default: async },
+    with: {
+        "wasi:http": wasmtime_wasi_http::bindings::http,
+        "wasi:logging": nero_wasi_logging::logging,
+        "nero:keyvalue-ttl": nero_keyvalue_ttl::keyvalue_ttl,
+    },
+});
+
+/// Bindings of the exports an extension may leave out, loaded from the same instance as the
+/// [`Extension`] when the component has them.
+pub mod optional {
+    wasmtime::component::bindgen!({
+        path: "./wit/v0.2.0-draft",
+        world: "libnero:extension/optional-exports",
+        exports: { default: async },
+        with: {
+            "wasi:http": wasmtime_wasi_http::bindings::http,
+            "nero:extension/types": super::nero::extension::types,
+        },
+    });
+}
+
+pub fn linker(engine: &Engine) -> Result<Linker<WasmState>> {
+    let mut linker = Linker::<WasmState>::new(engine);
+    wasmtime_wasi::p2::add_to_linker_async(&mut linker).unwrap();
+    wasmtime_wasi_http::add_only_http_to_linker_async(&mut linker).unwrap();
+    nero_wasi_logging::add_to_linker(&mut linker).unwrap();
+    nero_keyvalue_ttl::add_to_linker(&mut linker).unwrap();
+
+    Ok(linker)
+}
+
+impl From<Filter> for crate::types::Filter {
+    fn from(filter: Filter) -> Self {
+        crate::types::Filter {
+            id: filter.id,
+            display_name: filter.display_name,
+        }
+    }
+}
+
+impl From<FilterCategory> for crate::types::FilterCategory {
+    fn from(filter_category: FilterCategory) -> Self {
+        crate::types::FilterCategory {
+            id: filter_category.id,
+            display_name: filter_category.display_name,
+            filters: filter_category
+                .filters
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+        }
+    }
+}
+
+impl From<crate::types::SearchFilter> for SearchFilter {
+    fn from(search_filter: crate::types::SearchFilter) -> Self {
+        SearchFilter {
+            id: search_filter.id,
+            values: search_filter.values,
+        }
+    }
+}
+
+impl AsyncTryFromWithStore<SeriesPage> for crate::types::SeriesPage {
+    async fn try_from_with_store(
+        page: SeriesPage,
+        store: &mut wasmtime::Store<WasmState>,
+    ) -> anyhow::Result<Self> {
+        let mut items = Vec::new();
+        for series in page.series {
+            items.push(series.try_into_with_store(store).await?);
+        }
+        Ok(crate::types::SeriesPage {
+            items,
+            has_next_page: page.has_next_page,
+            current_page: page.current_page,
+            total_pages: page.total_pages,
+            total_items: page.total_items,
+        })
+    }
+}
+
+impl AsyncTryFromWithStore<Series> for crate::types::Series {
+    async fn try_from_with_store(
+        series: Series,
+        store: &mut wasmtime::Store<WasmState>,
+    ) -> anyhow::Result<Self> {
+        Ok(crate::types::Series {
+            id: series.id,
+            title: series.title,
+            poster_resource: match series.poster_resource {
+                Some(mr) => Some(mr.try_into_with_store(store).await?),
+                None => None,
+            },
+            synopsis: series.synopsis,
+            r#type: series.type_,
+        })
+    }
+}
+
+impl AsyncTryFromWithStore<EpisodesPage> for crate::types::EpisodesPage {
+    async fn try_from_with_store(
+        page: EpisodesPage,
+        store: &mut wasmtime::Store<WasmState>,
+    ) -> anyhow::Result<Self> {
+        let mut items = Vec::new();
+        for episode in page.episodes {
+            items.push(episode.try_into_with_store(store).await?);
+        }
+        Ok(crate::types::EpisodesPage {
+            items,
+            has_next_page: page.has_next_page,
+            current_page: page.current_page,
+            total_pages: page.total_pages,
+            total_items: page.total_items,
+        })
+    }
+}
+
+impl AsyncTryFromWithStore<Episode> for crate::types::Episode {
+    async fn try_from_with_store(
+        episode: Episode,
+        store: &mut wasmtime::Store<WasmState>,
+    ) -> anyhow::Result<Self> {
+        Ok(crate::types::Episode {
+            id: episode.id,
+            number: episode.number,
+            title: episode.title,
+            thumbnail_resource: match episode.thumbnail_resource {
+                Some(mr) => Some(mr.try_into_with_store(store).await?),
+                None => None,
+            },
+            description: episode.description,
+            next_episode_id: episode.next_episode_id,
+        })
+    }
+}
+
+impl AsyncTryFromWithStore<Video> for crate::types::Video {
+    async fn try_from_with_store(
+        video: Video,
+        store: &mut wasmtime::Store<WasmState>,
+    ) -> anyhow::Result<Self> {
+        Ok(crate::types::Video {
+            id: Some(video.id),
+            media_resource: video.media_resource.try_into_with_store(store).await?,
+            server: video.server,
+            resolution: video.resolution,
+            next_episode_id: video.next_episode_id,
+        })
+    }
+}
+
+impl AsyncTryFromWithStore<MediaResource> for crate::types::MediaResource {
+    async fn try_from_with_store(
+        media_resource: MediaResource,
+        store: &mut wasmtime::Store<WasmState>,
+    ) -> Result<Self> {
+        match media_resource {
+            MediaResource::HttpRequest(resource) => {
+                let outgoing: HostOutgoingRequest = resource.try_into_with_store(store).await?;
+                let request = outgoing.into_http_request().await?;
+                Ok(crate::types::MediaResource::HttpRequest {
+                    request: Box::new(request),
+                    mime_hint: None,
+                })
+            }
+            MediaResource::HintedHttpRequest(hinted) => {
+                let outgoing: HostOutgoingRequest =
+                    hinted.request.try_into_with_store(store).await?;
+                let request = outgoing.into_http_request().await?;
+                Ok(crate::types::MediaResource::HttpRequest {
+                    request: Box::new(request),
+                    mime_hint: Some(hinted.mime_type),
+                })
+            }
+            MediaResource::MagnetUri(uri) => {
+                MagnetURI::from_str(&uri)
+                    .map_err(|e| anyhow::anyhow!("Failed to parse magnet URI: {:?}", e))?;
+                Ok(crate::types::MediaResource::MagnetUri(uri))
+            }
+        }
+    }
+}
@@ -182,8 +182,17 @@ impl FileStore {
         Ok(!expired)
     }
 
-    pub fn list_keys(&self, cursor: Option<&str>) -> Result<(Vec<String>, Option<String>), Error> {
-        const PAGE_SIZE: usize = 100;
+    /// Lists up to `page_size` live keys sorted after `cursor`, along with the cursor of the next
+    /// page if there is one.
+    ///
+    /// The cursor is the last key of the previous page, so keys inserted or deleted between calls
+    /// never make a page fail, they are simply included or skipped.
+    pub fn list_keys(
+        &self,
+        cursor: Option<&str>,
+        page_size: usize,
+    ) -> Result<(Vec<String>, Option<String>), Error> {
+        let page_size = page_size.max(1);
 
         let mut keys = Vec::new();
         let mut stack = vec![self.root.clone()];
@@ -217,10 +226,10 @@ impl FileStore {
         let total = keys.len();
         let page = keys[start..]
             .iter()
-            .take(PAGE_SIZE)
+            .take(page_size)
             .cloned()
             .collect::<Vec<_>>();
-        let next_cursor = (start + PAGE_SIZE < total)
+        let next_cursor = (start + page_size < total)
             .then(|| page.last().cloned())
             .flatten();
 
@@ -293,6 +302,27 @@ mod tests {
         assert!(matches!(err, Error::StorageLimitExceeded));
     }
 
+    #[test]
+    fn fills_up_to_limit_then_rejects() {
+        let entry_bytes = {
+            let (store, _dir) = store(None);
+            store.set("k1", vec![0u8; 32], None).unwrap();
+            *store.size_bytes.lock().unwrap()
+        };
+
+        let (store, _dir) = store(Some(entry_bytes * 2));
+        store.set("k1", vec![0u8; 32], None).unwrap();
+        store.set("k2", vec![0u8; 32], None).unwrap();
+
+        let err = store.set("k3", vec![0u8; 32], None).unwrap_err();
+        assert!(matches!(err, Error::StorageLimitExceeded));
+
+        // Overwriting at full capacity only counts the size difference.
+        store.set("k2", vec![1u8; 32], None).unwrap();
+        let err = store.set("k2", vec![1u8; 33], None).unwrap_err();
+        assert!(matches!(err, Error::StorageLimitExceeded));
+    }
+
     #[test]
     fn overwrite_same_key_does_not_accumulate_size() {
         let (store, _dir) = store(Some(1024));
@@ -311,11 +341,30 @@ mod tests {
         store.set("dead", b"4".to_vec(), Some(1)).unwrap();
         std::thread::sleep(std::time::Duration::from_millis(5));
 
-        let (keys, cursor) = store.list_keys(None).unwrap();
+        let (keys, cursor) = store.list_keys(None, 100).unwrap();
         assert_eq!(keys, vec!["alpha", "beta", "gamma"]);
         assert!(cursor.is_none());
     }
 
+    #[test]
+    fn list_keys_pages_survive_deleted_cursor() {
+        let (store, _dir) = store(None);
+        for key in ["a", "b", "c", "d", "e"] {
+            store.set(key, b"1".to_vec(), None).unwrap();
+        }
+
+        let (keys, cursor) = store.list_keys(None, 2).unwrap();
+        assert_eq!(keys, vec!["a", "b"]);
+        assert_eq!(cursor.as_deref(), Some("b"));
+
+        store.delete("b").unwrap();
+        store.delete("c").unwrap();
+
+        let (keys, cursor) = store.list_keys(cursor.as_deref(), 2).unwrap();
+        assert_eq!(keys, vec!["d", "e"]);
+        assert!(cursor.is_none());
+    }
+
     #[test]
     fn concurrent_writes_respect_size_limit() {
         let dir = tempdir().unwrap();
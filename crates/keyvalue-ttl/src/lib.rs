@@ -48,8 +48,12 @@ impl From<FileStoreError> for Error {
 
 pub struct Bucket;
 
+/// Number of keys returned by a `list-keys` call unless configured otherwise.
+pub const DEFAULT_LIST_PAGE_SIZE: usize = 100;
+
 pub struct KeyValueTTLCtx {
     store: Arc<FileStore>,
+    list_page_size: usize,
 }
 
 impl KeyValueTTLCtx {
@@ -60,8 +64,15 @@ impl KeyValueTTLCtx {
 
         Ok(Self {
             store: Arc::new(store),
+            list_page_size: DEFAULT_LIST_PAGE_SIZE,
         })
     }
+
+    /// Caps how many keys a single `list-keys` call returns, to bound guest memory.
+    pub fn with_list_page_size(mut self, page_size: usize) -> Self {
+        self.list_page_size = page_size;
+        self
+    }
 }
 
 pub struct KeyValueTTL<'a> {
@@ -132,7 +143,8 @@ impl keyvalue_ttl::store::HostBucket for KeyValueTTL<'_> {
         cursor: Option<String>,
     ) -> Result<keyvalue_ttl::store::KeyResponse, Error> {
         let store = self.ctx.store.clone();
-        let result = spawn_blocking(move || store.list_keys(cursor.as_deref()))
+        let page_size = self.ctx.list_page_size;
+        let result = spawn_blocking(move || store.list_keys(cursor.as_deref(), page_size))
             .await
             .unwrap()?;
 
@@ -162,3 +174,228 @@ struct HasKeyValueTTL;
 impl HasData for HasKeyValueTTL {
     type Data<'a> = KeyValueTTL<'a>;
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use nero_file_store::FileStore;
+    use tempfile::tempdir;
+    use wasmtime::{
+        Config, Engine, Store,
+        component::{Component, Instance, Linker, Resource, ResourceTable},
+    };
+
+    use crate::{
+        Bucket, DEFAULT_LIST_PAGE_SIZE, Error, KeyValueTTL, KeyValueTTLCtx, KeyValueTTLView,
+        add_to_linker,
+        keyvalue_ttl::store::{self, Host, HostBucket},
+    };
+
+    fn ctx(max_bytes: Option<u64>) -> (KeyValueTTLCtx, tempfile::TempDir) {
+        let dir = tempdir().unwrap();
+        let store = FileStore::new(dir.path().to_path_buf(), max_bytes).unwrap();
+        let ctx = KeyValueTTLCtx {
+            store: Arc::new(store),
+            list_page_size: DEFAULT_LIST_PAGE_SIZE,
+        };
+        (ctx, dir)
+    }
+
+    #[test]
+    fn convert_error_maps_every_variant() {
+        let (ctx, _dir) = ctx(None);
+        let mut table = ResourceTable::new();
+        let mut kv = KeyValueTTL::new(&ctx, &mut table);
+
+        assert!(matches!(
+            kv.convert_error(Error::NoSuchBucket).unwrap(),
+            store::Error::NoSuchBucket
+        ));
+        assert!(matches!(
+            kv.convert_error(Error::AccessDenied).unwrap(),
+            store::Error::AccessDenied
+        ));
+        assert!(matches!(
+            kv.convert_error(Error::StorageLimitExceeded).unwrap(),
+            store::Error::StorageLimitExceeded
+        ));
+        assert!(matches!(
+            kv.convert_error(Error::Other("boom".into())).unwrap(),
+            store::Error::Other(msg) if msg == "boom"
+        ));
+    }
+
+    #[tokio::test]
+    async fn open_unknown_bucket_returns_no_such_bucket() {
+        let (ctx, _dir) = ctx(None);
+        let mut table = ResourceTable::new();
+        let mut kv = KeyValueTTL::new(&ctx, &mut table);
+
+        let err = kv.open("unknown".into()).await.unwrap_err();
+        assert!(matches!(
+            kv.convert_error(err).unwrap(),
+            store::Error::NoSuchBucket
+        ));
+    }
+
+    #[tokio::test]
+    async fn set_over_limit_returns_storage_limit_exceeded() {
+        let (ctx, _dir) = ctx(Some(16));
+        let mut table = ResourceTable::new();
+        let bucket = table.push(Bucket).unwrap();
+        let mut kv = KeyValueTTL::new(&ctx, &mut table);
+
+        let err = kv
+            .set(bucket, "key".into(), vec![0u8; 32], None)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            kv.convert_error(err).unwrap(),
+            store::Error::StorageLimitExceeded
+        ));
+    }
+
+    /// A guest returning the case of the `error` it receives from `open("unknown")` and from
+    /// setting a 32 byte value, 255 when the call succeeds.
+    const GUEST: &str = r#"
+    (component
+      (import "nero:keyvalue-ttl/store@0.1.0-draft" (instance $store
+        (export "bucket" (type $bucket (sub resource)))
+        (type $error (variant
+          (case "no-such-bucket")
+          (case "access-denied")
+          (case "storage-limit-exceeded")
+          (case "other" string)))
+        (export "error" (type $error' (eq $error)))
+        (export "open" (func
+          (param "identifier" string)
+          (result (result (own $bucket) (error $error')))))
+        (export "[method]bucket.set" (func
+          (param "self" (borrow $bucket))
+          (param "key" string)
+          (param "value" (list u8))
+          (param "ttl-ms" (option u32))
+          (result (result (error $error')))))))
+      (alias export $store "open" (func $open))
+      (alias export $store "[method]bucket.set" (func $set))
+
+      (core module $memory
+        (memory (export "memory") 1)
+        (global $next (mut i32) (i32.const 1024))
+        (func (export "realloc") (param i32 i32 i32 i32) (result i32)
+          (global.get $next)
+          (global.set $next (i32.add (global.get $next) (local.get 3)))))
+      (core instance $memory (instantiate $memory))
+
+      (core func $open (canon lower (func $open)
+        (memory $memory "memory") (realloc (func $memory "realloc"))))
+      (core func $set (canon lower (func $set)
+        (memory $memory "memory") (realloc (func $memory "realloc"))))
+
+      ;; Both functions return the case of the error the guest received, 255 on success.
+      (core module $guest
+        (import "memory" "memory" (memory 1))
+        (import "store" "open" (func $open (param i32 i32 i32)))
+        (import "store" "set" (func $set (param i32 i32 i32 i32 i32 i32 i32 i32)))
+        (data (i32.const 0) "unknown")
+        (data (i32.const 8) "key")
+        (func (export "open-unknown") (result i32)
+          (call $open (i32.const 0) (i32.const 7) (i32.const 64))
+          (if (i32.eqz (i32.load8_u (i32.const 64)))
+            (then (return (i32.const 255))))
+          (i32.load8_u (i32.const 68)))
+        (func (export "set-large") (result i32)
+          (call $open (i32.const 0) (i32.const 0) (i32.const 64))
+          (if (i32.load8_u (i32.const 64))
+            (then unreachable))
+          ;; 32 zero bytes at offset 16 as the value, no TTL.
+          (call $set
+            (i32.load (i32.const 68))
+            (i32.const 8) (i32.const 3)
+            (i32.const 16) (i32.const 32)
+            (i32.const 0) (i32.const 0)
+            (i32.const 80))
+          (if (i32.eqz (i32.load8_u (i32.const 80)))
+            (then (return (i32.const 255))))
+          (i32.load8_u (i32.const 84))))
+      (core instance $guest (instantiate $guest
+        (with "memory" (instance $memory))
+        (with "store" (instance
+          (export "open" (func $open))
+          (export "set" (func $set))))))
+
+      (func (export "open-unknown") (result u8)
+        (canon lift (core func $guest "open-unknown")))
+      (func (export "set-large") (result u8)
+        (canon lift (core func $guest "set-large"))))
+"#;
+
+    struct GuestState {
+        ctx: KeyValueTTLCtx,
+        table: ResourceTable,
+    }
+
+    impl KeyValueTTLView for GuestState {
+        fn keyvalue_ttl(&mut self) -> KeyValueTTL<'_> {
+            KeyValueTTL::new(&self.ctx, &mut self.table)
+        }
+    }
+
+    #[tokio::test]
+    async fn guest_receives_converted_errors() {
+        let (ctx, _dir) = ctx(Some(16));
+        let mut config = Config::new();
+        config.async_support(true);
+        let engine = Engine::new(&config).unwrap();
+        let component = Component::new(&engine, GUEST).unwrap();
+
+        let mut linker = Linker::new(&engine);
+        add_to_linker(&mut linker).unwrap();
+        let mut store = Store::new(
+            &engine,
+            GuestState {
+                ctx,
+                table: ResourceTable::new(),
+            },
+        );
+        let instance = linker
+            .instantiate_async(&mut store, &component)
+            .await
+            .unwrap();
+
+        // Cases of `error` in declaration order.
+        let case = call_guest(&mut store, &instance, "open-unknown").await;
+        assert_eq!(case, 0, "no-such-bucket");
+        let case = call_guest(&mut store, &instance, "set-large").await;
+        assert_eq!(case, 2, "storage-limit-exceeded");
+    }
+
+    async fn call_guest(store: &mut Store<GuestState>, instance: &Instance, name: &str) -> u8 {
+        let func = instance
+            .get_typed_func::<(), (u8,)>(&mut *store, name)
+            .unwrap();
+        let (case,) = func.call_async(&mut *store, ()).await.unwrap();
+        func.post_return_async(&mut *store).await.unwrap();
+        case
+    }
+
+    #[test]
+    fn resource_table_errors_map_to_other() {
+        let (ctx, _dir) = ctx(None);
+        let mut table = ResourceTable::new();
+        let bucket = table.push(Bucket).unwrap();
+        let rep = bucket.rep();
+        table.delete(bucket).unwrap();
+        let err: Error = table
+            .delete(Resource::<Bucket>::new_own(rep))
+            .unwrap_err()
+            .into();
+
+        let mut kv = KeyValueTTL::new(&ctx, &mut table);
+        assert!(matches!(
+            kv.convert_error(err).unwrap(),
+            store::Error::Other(_)
+        ));
+    }
+}
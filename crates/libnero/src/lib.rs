@@ -1,35 +1,78 @@
+mod metrics;
 pub mod types;
 mod utils;
 
-use nero_media_proxy::MediaProxy;
+use nero_media_proxy::{MediaProxy, resources::UnsupportedMediaType};
 pub use wasm_metadata::Metadata as ExtensionMetadata;
 
-use std::{path::Path, sync::Arc};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
 
 use anyhow::bail;
-use nero_extensions::{Extension as ExtensionTrait, WasmExtension, WasmHost};
+use nero_extensions::{
+    CallMetrics, Extension as ExtensionTrait, WasmExtension, WasmHost,
+    types::MediaResource as ExtensionMediaResource,
+};
+use url::Url;
 use wasm_metadata::Payload;
 
 use crate::{
+    metrics::LoadedExtensions,
     types::{
-        EpisodesPage, ExtensionOptions, FilterCategory, SearchFilter, Series, SeriesPage, Video,
+        EpisodesPage, ExtensionDescription, ExtensionOptions, FallbackSearchResult, FilterCategory,
+        SearchFilter, Series, SeriesPage, UnsupportedMediaPolicy, Video,
     },
-    utils::AyncTryIntoWithProxy,
+    utils::{AyncTryIntoWithProxy, clone_metadata},
 };
 
 pub struct ExtensionHost {
     host: WasmHost,
     proxy: Arc<MediaProxy>,
+    /// Parsed extension metadata keyed by file path, along with the file's modification time.
+    metadata_cache: Mutex<HashMap<PathBuf, (SystemTime, ExtensionMetadata)>>,
+    loaded: Arc<LoadedExtensions>,
 }
 
 impl ExtensionHost {
+    /// Also serves the call metrics of the extensions loaded through the host at the proxy's
+    /// `/metrics` route, labelled by extension name and method.
     pub fn new(proxy: MediaProxy) -> Self {
+        let loaded = Arc::new(LoadedExtensions::default());
+        proxy.set_metrics_exporter(loaded.clone());
+
         Self {
             host: WasmHost::default(),
             proxy: Arc::new(proxy),
+            metadata_cache: Mutex::default(),
+            loaded,
         }
     }
 
+    /// Limits how many extension calls run at once across every extension loaded afterwards.
+    /// Further calls wait for one to finish instead of failing.
+    pub fn with_max_concurrent_calls(mut self, limit: usize) -> Self {
+        self.host = self.host.with_max_concurrent_calls(limit);
+        self
+    }
+
+    /// Fails extension calls running longer than `timeout` instead of waiting on them. See
+    /// [`WasmHost::with_call_timeout`].
+    pub fn with_call_timeout(mut self, timeout: Duration) -> Self {
+        self.host = self.host.with_call_timeout(timeout);
+        self
+    }
+
+    /// Caches compiled extensions in `dir`, so loading them again, in this run or a later one,
+    /// skips compilation. See [`WasmHost::with_compilation_cache`].
+    pub fn with_compilation_cache(mut self, dir: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        self.host = self.host.with_compilation_cache(dir)?;
+        Ok(self)
+    }
+
     pub fn media_proxy(&self) -> &Arc<MediaProxy> {
         &self.proxy
     }
@@ -39,10 +82,22 @@ impl ExtensionHost {
         file_path: impl AsRef<Path>,
         options: ExtensionOptions,
     ) -> anyhow::Result<Extension> {
-        let extension = self
-            .host
-            .load_extension_async(file_path, options.into())
-            .await?;
+        let bytes = tokio::fs::read(file_path).await?;
+        self.load_from_bytes(&bytes, options).await
+    }
+
+    /// Loads an extension from its component bytes instead of a file.
+    pub async fn load_from_bytes(
+        &self,
+        wasm_bytes: &[u8],
+        options: ExtensionOptions,
+    ) -> anyhow::Result<Extension> {
+        let extension = Arc::new(
+            self.host
+                .load_extension_from_bytes_async(wasm_bytes, options.into())
+                .await?,
+        );
+        self.loaded.add(Arc::downgrade(&extension));
 
         Ok(Extension {
             inner: extension,
@@ -50,11 +105,43 @@ impl ExtensionHost {
         })
     }
 
+    /// Reads an extension's metadata from its file.
+    ///
+    /// The result is cached until the file's modification time changes.
     pub async fn get_extension_metadata(
+        &self,
         file_path: impl AsRef<Path>,
     ) -> anyhow::Result<ExtensionMetadata> {
-        let bytes = tokio::fs::read(file_path).await?;
-        let payload = Payload::from_binary(&bytes)?;
+        let path = file_path.as_ref();
+        let modified = tokio::fs::metadata(path).await?.modified()?;
+
+        if let Some((cached_at, metadata)) = self.metadata_cache.lock().unwrap().get(path)
+            && *cached_at == modified
+        {
+            return Ok(clone_metadata(metadata));
+        }
+
+        let bytes = tokio::fs::read(path).await?;
+        let metadata = Self::get_extension_metadata_from_bytes(&bytes)?;
+        self.metadata_cache
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), (modified, clone_metadata(&metadata)));
+
+        Ok(metadata)
+    }
+
+    /// Forgets the metadata cached by [`Self::get_extension_metadata`], e.g. after extension
+    /// files were replaced in place with the same modification time.
+    pub fn clear_metadata_cache(&self) {
+        self.metadata_cache.lock().unwrap().clear();
+    }
+
+    /// Reads an extension's metadata from its component bytes instead of a file.
+    pub fn get_extension_metadata_from_bytes(
+        wasm_bytes: &[u8],
+    ) -> anyhow::Result<ExtensionMetadata> {
+        let payload = Payload::from_binary(wasm_bytes)?;
         match payload {
             Payload::Component { metadata, .. } => Ok(metadata),
             Payload::Module(_) => bail!("unsupported wasm module"),
@@ -63,7 +150,7 @@ impl ExtensionHost {
 }
 
 pub struct Extension {
-    inner: WasmExtension,
+    inner: Arc<WasmExtension>,
     proxy: Arc<MediaProxy>,
 }
 
@@ -72,11 +159,50 @@ impl Extension {
         self.inner.metadata()
     }
 
+    /// The extension's name, as declared in its metadata.
+    pub fn name(&self) -> Option<String> {
+        self.metadata().name.clone()
+    }
+
+    /// The extension's version, as declared in its metadata.
+    pub fn version(&self) -> Option<String> {
+        self.metadata().version.as_ref().map(ToString::to_string)
+    }
+
+    /// The extension's authors, as declared in its metadata.
+    pub fn authors(&self) -> Option<String> {
+        self.metadata().authors.as_ref().map(ToString::to_string)
+    }
+
+    /// Returns call counts, failures and timings of each extension method.
+    pub fn metrics(&self) -> HashMap<&'static str, CallMetrics> {
+        self.inner.metrics()
+    }
+
+    /// Identifies this extension in the `source_id` of the items it returns.
+    fn source_id(&self) -> Option<String> {
+        self.name()
+    }
+
     pub async fn get_filters(&self) -> anyhow::Result<Vec<FilterCategory>> {
         let categories = self.inner.filters().await?;
         Ok(categories.into_iter().map(Into::into).collect())
     }
 
+    /// Returns the extension's metadata together with the filters it supports.
+    pub async fn describe(&self) -> anyhow::Result<ExtensionDescription> {
+        let metadata = self.metadata();
+        let filters = self.get_filters().await?;
+
+        Ok(ExtensionDescription {
+            name: self.name(),
+            version: self.version(),
+            description: metadata.description.as_ref().map(ToString::to_string),
+            authors: self.authors(),
+            filters,
+        })
+    }
+
     pub async fn search(
         &self,
         query: &str,
@@ -85,12 +211,18 @@ impl Extension {
     ) -> anyhow::Result<SeriesPage> {
         let ext_filters = filters.into_iter().map(Into::into).collect();
         let page = self.inner.search(query, page, ext_filters).await?;
-        page.async_try_into_with_proxy(&self.proxy).await
+        let mut page: SeriesPage = page.async_try_into_with_proxy(&self.proxy).await?;
+        for series in &mut page.items {
+            series.source_id = self.source_id();
+        }
+        Ok(page)
     }
 
     pub async fn get_series_info(&self, series_id: &str) -> anyhow::Result<Series> {
         let series = self.inner.get_series_info(series_id).await?;
-        series.async_try_into_with_proxy(&self.proxy).await
+        let mut series: Series = series.async_try_into_with_proxy(&self.proxy).await?;
+        series.source_id = self.source_id();
+        Ok(series)
     }
 
     pub async fn get_series_episodes(
@@ -99,22 +231,136 @@ impl Extension {
         page: Option<u16>,
     ) -> anyhow::Result<EpisodesPage> {
         let page = self.inner.get_series_episodes(series_id, page).await?;
-        page.async_try_into_with_proxy(&self.proxy).await
+        let mut page: EpisodesPage = page.async_try_into_with_proxy(&self.proxy).await?;
+        for episode in &mut page.items {
+            episode.source_id = self.source_id();
+        }
+        Ok(page)
     }
 
     pub async fn get_series_videos(
         &self,
         series_id: &str,
         episode_id: &str,
+    ) -> anyhow::Result<Vec<Video>> {
+        self.get_series_videos_with_policy(series_id, episode_id, UnsupportedMediaPolicy::Fail)
+            .await
+    }
+
+    /// Resolves again the single video identified by `video_id`, registering only its resource
+    /// with the proxy, e.g. to refresh a deep link whose URL expired. `None` when the extension
+    /// no longer lists it. Fails for extensions built against 0.1.0-draft.
+    pub async fn get_video(
+        &self,
+        series_id: &str,
+        episode_id: &str,
+        video_id: &str,
+    ) -> anyhow::Result<Option<Video>> {
+        let Some(video) = self
+            .inner
+            .get_video(series_id, episode_id, video_id)
+            .await?
+        else {
+            return Ok(None);
+        };
+
+        let video: Video = video.async_try_into_with_proxy(&self.proxy).await?;
+        Ok(Some(Video {
+            source_id: self.source_id(),
+            ..video
+        }))
+    }
+
+    /// Like [`Self::get_series_videos`], handling videos the proxy can't serve according to
+    /// `policy` instead of failing.
+    pub async fn get_series_videos_with_policy(
+        &self,
+        series_id: &str,
+        episode_id: &str,
+        policy: UnsupportedMediaPolicy,
     ) -> anyhow::Result<Vec<Video>> {
         let extension_videos = self.inner.get_series_videos(series_id, episode_id).await?;
 
-        let mut videos = Vec::with_capacity(extension_videos.len());
-        for video in extension_videos {
-            let video = video.async_try_into_with_proxy(&self.proxy).await?;
-            videos.push(video);
+        // Videos are registered and probed concurrently, a slow server only delays its own.
+        let videos = futures_util::future::join_all(
+            extension_videos
+                .into_iter()
+                .map(|video| self.convert_video(video, policy)),
+        )
+        .await;
+
+        Ok(videos
+            .into_iter()
+            .collect::<anyhow::Result<Vec<_>>>()?
+            .into_iter()
+            .flatten()
+            .collect())
+    }
+
+    /// Registers a video with the proxy, `None` when `policy` leaves it out.
+    async fn convert_video(
+        &self,
+        video: nero_extensions::types::Video,
+        policy: UnsupportedMediaPolicy,
+    ) -> anyhow::Result<Option<Video>> {
+        let direct_request = match &video.media_resource {
+            ExtensionMediaResource::HttpRequest { request, .. } => Some(request.clone()),
+            ExtensionMediaResource::MagnetUri(_) => None,
+        };
+        let (id, server, resolution) = (video.id.clone(), video.server.clone(), video.resolution);
+        let next_episode_id = video.next_episode_id.clone();
+
+        match video.async_try_into_with_proxy(&self.proxy).await {
+            Ok(video) => Ok(Some(Video {
+                source_id: self.source_id(),
+                ..video
+            })),
+            Err(err) if err.is::<UnsupportedMediaType>() => match (policy, direct_request) {
+                (UnsupportedMediaPolicy::Direct, Some(req)) => Ok(Some(Video {
+                    id,
+                    url: Url::parse(&req.uri().to_string())?,
+                    server,
+                    resolution,
+                    proxied: false,
+                    is_live: self.proxy.resource_store().probe_live_stream(&req).await,
+                    next_episode_id,
+                    source_id: self.source_id(),
+                })),
+                (UnsupportedMediaPolicy::Skip, _) => Ok(None),
+                _ => Err(err),
+            },
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Searches each extension in turn and returns the first page with results.
+///
+/// An extension that fails is skipped, its error is only returned if every extension failed.
+pub async fn search_with_fallback(
+    extensions: &[&Extension],
+    query: &str,
+    page: Option<u16>,
+    filters: Vec<SearchFilter>,
+) -> anyhow::Result<Option<FallbackSearchResult>> {
+    let mut last_error = None;
+    let mut any_succeeded = false;
+
+    for (extension_index, extension) in extensions.iter().enumerate() {
+        match extension.search(query, page, filters.clone()).await {
+            Ok(page) if !page.items.is_empty() => {
+                return Ok(Some(FallbackSearchResult {
+                    extension_index,
+                    page,
+                }));
+            }
+            Ok(_) => any_succeeded = true,
+            Err(err) => last_error = Some(err),
         }
+    }
 
-        Ok(videos)
+    match last_error {
+        Some(err) if !any_succeeded => Err(err),
+        _ => Ok(None),
     }
 }
@@ -0,0 +1,39 @@
+use std::sync::{Mutex, Weak};
+
+use nero_extensions::{Extension as _, WasmExtension, encode_prometheus};
+use nero_media_proxy::stats::MetricsExporter;
+
+/// Extensions loaded by a host, whose call metrics the media proxy serves at `/metrics`.
+#[derive(Default)]
+pub(crate) struct LoadedExtensions(Mutex<Vec<Weak<WasmExtension>>>);
+
+impl LoadedExtensions {
+    pub(crate) fn add(&self, extension: Weak<WasmExtension>) {
+        let mut extensions = self.0.lock().unwrap();
+        extensions.retain(|extension| extension.strong_count() > 0);
+        extensions.push(extension);
+    }
+}
+
+impl MetricsExporter for LoadedExtensions {
+    fn export(&self) -> String {
+        let extensions = self
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect::<Vec<_>>();
+        let names = extensions
+            .iter()
+            .map(|extension| extension.metadata().name.clone().unwrap_or_default())
+            .collect::<Vec<_>>();
+
+        encode_prometheus(
+            names
+                .iter()
+                .zip(&extensions)
+                .map(|(name, extension)| (name.as_str(), extension.metrics())),
+        )
+    }
+}
@@ -2,7 +2,7 @@ use std::path::PathBuf;
 
 use anyhow::bail;
 use nero_extensions::types::MediaResource;
-use nero_media_proxy::{MediaProxy, resources::Resource};
+use nero_media_proxy::{HttpRequest, MediaProxy, resources::Resource};
 use serde::{Deserialize, Serialize};
 use url::Url;
 use uuid::Uuid;
@@ -10,10 +10,20 @@ use uuid::Uuid;
 use crate::utils::AsyncTryFromWithProxy;
 
 #[derive(Debug, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[cfg_attr(not(feature = "snake-case"), serde(rename_all = "camelCase"))]
 pub struct ExtensionOptions {
     pub cache_dir: PathBuf,
     pub max_cache_size: Option<u64>,
+    /// Given to the extension as its standard input.
+    #[serde(default)]
+    pub stdin: Vec<u8>,
+    /// Maximum number of calls to the extension running at once, the rest wait their turn.
+    #[serde(default)]
+    pub max_concurrent_calls: Option<usize>,
+    /// Number of instances kept warm between calls, which then carry the guest's state over
+    /// from one call to the next. Disabled by default.
+    #[serde(default)]
+    pub instance_pool_size: usize,
 }
 
 impl From<ExtensionOptions> for nero_extensions::ExtensionOptions {
@@ -21,15 +31,34 @@ impl From<ExtensionOptions> for nero_extensions::ExtensionOptions {
         Self {
             cache_dir: options.cache_dir,
             max_cache_size: options.max_cache_size,
+            stdin: options.stdin.into(),
+            max_concurrent_calls: options.max_concurrent_calls,
+            instance_pool_size: options.instance_pool_size,
         }
     }
 }
 
 #[derive(Debug, Serialize)]
-#[serde(rename_all = "camelCase")]
+#[cfg_attr(not(feature = "snake-case"), serde(rename_all = "camelCase"))]
+pub struct ExtensionDescription {
+    pub name: Option<String>,
+    pub version: Option<String>,
+    pub description: Option<String>,
+    pub authors: Option<String>,
+    pub filters: Vec<FilterCategory>,
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(not(feature = "snake-case"), serde(rename_all = "camelCase"))]
 pub struct Page<T> {
     pub items: Vec<T>,
     pub has_next_page: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current_page: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_pages: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_items: Option<u32>,
 }
 
 impl<T, U> AsyncTryFromWithProxy<nero_extensions::types::Page<T>> for Page<U>
@@ -47,21 +76,86 @@ where
         Ok(Self {
             items,
             has_next_page: page.has_next_page,
+            current_page: page.current_page,
+            total_pages: page.total_pages,
+            total_items: page.total_items,
         })
     }
 }
 
 pub type SeriesPage = Page<Series>;
+
+/// The first non-empty page found by [`crate::search_with_fallback`].
+#[derive(Debug, Serialize)]
+#[cfg_attr(not(feature = "snake-case"), serde(rename_all = "camelCase"))]
+pub struct FallbackSearchResult {
+    /// Position of the extension that produced the page in the order it was tried.
+    pub extension_index: usize,
+    pub page: SeriesPage,
+}
 pub type EpisodesPage = Page<Episode>;
 
+/// Registers an image with the proxy, along with its dimensions when they can be probed.
+async fn register_image(
+    resource: Option<MediaResource>,
+    proxy: &MediaProxy,
+) -> anyhow::Result<(Option<Url>, Option<(u32, u32)>)> {
+    match resource {
+        Some(MediaResource::HttpRequest { request, mime_hint }) => {
+            #[cfg(feature = "image-dimensions")]
+            let dimensions = proxy
+                .resource_store()
+                .probe_image_dimensions(&request)
+                .await;
+            #[cfg(not(feature = "image-dimensions"))]
+            let dimensions = None;
+
+            let url = register_http(request, mime_hint, proxy).await?;
+            Ok((Some(url), dimensions))
+        }
+        Some(MediaResource::MagnetUri(_)) => {
+            bail!("Magnet URIs are not supported for images");
+        }
+        None => Ok((None, None)),
+    }
+}
+
+/// Registers an HTTP request with the proxy, skipping MIME type detection when the extension
+/// declared a type.
+async fn register_http(
+    request: Box<HttpRequest>,
+    mime_hint: Option<String>,
+    proxy: &MediaProxy,
+) -> anyhow::Result<Url> {
+    let id = Uuid::new_v4().to_string();
+    match mime_hint {
+        Some(hint) => {
+            proxy
+                .resource_store()
+                .insert_with_mime_hint(id, request, &hint)
+                .await
+        }
+        None => {
+            proxy
+                .resource_store()
+                .insert(id, Resource::Http(request))
+                .await
+        }
+    }
+}
+
 #[derive(Debug, Serialize)]
-#[serde(rename_all = "camelCase")]
+#[cfg_attr(not(feature = "snake-case"), serde(rename_all = "camelCase"))]
 pub struct Series {
     pub id: String,
     pub title: String,
     pub poster_url: Option<Url>,
+    pub poster_width: Option<u32>,
+    pub poster_height: Option<u32>,
     pub synopsis: Option<String>,
     pub r#type: Option<String>,
+    /// Name of the extension this series came from.
+    pub source_id: Option<String>,
 }
 
 impl AsyncTryFromWithProxy<nero_extensions::types::Series> for Series {
@@ -69,34 +163,35 @@ impl AsyncTryFromWithProxy<nero_extensions::types::Series> for Series {
         series: nero_extensions::types::Series,
         proxy: &MediaProxy,
     ) -> anyhow::Result<Self> {
+        let (poster_url, poster_size) = register_image(series.poster_resource, proxy).await?;
+
         Ok(Self {
             id: series.id,
             title: series.title,
-            poster_url: match series.poster_resource {
-                Some(MediaResource::HttpRequest(req)) => {
-                    let id = Uuid::new_v4().to_string();
-                    let resource = Resource::Http(req);
-                    Some(proxy.resource_store().insert(id, resource).await?)
-                }
-                Some(MediaResource::MagnetUri(_)) => {
-                    bail!("Magnet URIs are not supported for images");
-                }
-                None => None,
-            },
+            poster_url,
+            poster_width: poster_size.map(|(width, _)| width),
+            poster_height: poster_size.map(|(_, height)| height),
             synopsis: series.synopsis,
             r#type: series.r#type,
+            source_id: None,
         })
     }
 }
 
 #[derive(Debug, Serialize)]
-#[serde(rename_all = "camelCase")]
+#[cfg_attr(not(feature = "snake-case"), serde(rename_all = "camelCase"))]
 pub struct Episode {
     pub id: String,
     pub number: u16,
     pub title: Option<String>,
     pub thumbnail_url: Option<Url>,
+    pub thumbnail_width: Option<u32>,
+    pub thumbnail_height: Option<u32>,
     pub description: Option<String>,
+    /// Identifier of the following episode, when the extension knows it.
+    pub next_episode_id: Option<String>,
+    /// Name of the extension this episode came from.
+    pub source_id: Option<String>,
 }
 
 impl AsyncTryFromWithProxy<nero_extensions::types::Episode> for Episode {
@@ -104,33 +199,56 @@ impl AsyncTryFromWithProxy<nero_extensions::types::Episode> for Episode {
         episode: nero_extensions::types::Episode,
         proxy: &MediaProxy,
     ) -> anyhow::Result<Self> {
+        let (thumbnail_url, thumbnail_size) =
+            register_image(episode.thumbnail_resource, proxy).await?;
+
         Ok(Self {
             id: episode.id,
             number: episode.number,
             title: episode.title,
-            thumbnail_url: match episode.thumbnail_resource {
-                Some(MediaResource::HttpRequest(req)) => {
-                    let id = Uuid::new_v4().to_string();
-                    let resource = Resource::Http(req);
-                    Some(proxy.resource_store().insert(id, resource).await?)
-                }
-                Some(MediaResource::MagnetUri(_)) => {
-                    bail!("Magnet URIs are not supported for images");
-                }
-                None => None,
-            },
+            thumbnail_url,
+            thumbnail_width: thumbnail_size.map(|(width, _)| width),
+            thumbnail_height: thumbnail_size.map(|(_, height)| height),
             description: episode.description,
+            next_episode_id: episode.next_episode_id,
+            source_id: None,
         })
     }
 }
 
 type Resolution = (u16, u16);
 
+/// What to do with a video whose media type the proxy doesn't support.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnsupportedMediaPolicy {
+    /// Fail the whole call.
+    #[default]
+    Fail,
+    /// Return the upstream URL as is, with `proxied` set to `false`. Headers the extension set
+    /// on the request are lost, so the URL may not play without them.
+    Direct,
+    /// Leave the video out.
+    Skip,
+}
+
 #[derive(Debug, Serialize)]
+#[cfg_attr(not(feature = "snake-case"), serde(rename_all = "camelCase"))]
 pub struct Video {
+    /// Identifies the video among the ones of its episode, to resolve it again with
+    /// [`crate::Extension::get_video`]. Only provided by extensions built against 0.2.0-draft.
+    pub id: Option<String>,
     pub url: Url,
     pub server: String,
     pub resolution: Resolution,
+    pub proxied: bool,
+    /// Whether this is a live stream, which can't be seeked and has no duration. Only HLS
+    /// playlists are checked, anything else is VOD.
+    pub is_live: bool,
+    /// Identifier of the episode after this video's one, when the extension knows it.
+    pub next_episode_id: Option<String>,
+    /// Name of the extension this video came from.
+    pub source_id: Option<String>,
 }
 
 impl AsyncTryFromWithProxy<nero_extensions::types::Video> for Video {
@@ -138,11 +256,17 @@ impl AsyncTryFromWithProxy<nero_extensions::types::Video> for Video {
         video: nero_extensions::types::Video,
         proxy: &MediaProxy,
     ) -> anyhow::Result<Self> {
+        let mut is_live = false;
         let url = match video.media_resource {
-            nero_extensions::types::MediaResource::HttpRequest(request) => {
-                let id = Uuid::new_v4().to_string();
-                let resource = Resource::Http(request);
-                proxy.resource_store().insert(id, resource).await
+            nero_extensions::types::MediaResource::HttpRequest { request, mime_hint } => {
+                let probed = request.clone();
+                let (live, url) = futures_util::future::join(
+                    proxy.resource_store().probe_live_stream(&probed),
+                    register_http(request, mime_hint, proxy),
+                )
+                .await;
+                is_live = live;
+                url
             }
             #[cfg(not(feature = "torrent"))]
             nero_extensions::types::MediaResource::MagnetUri(_) => {
@@ -159,15 +283,20 @@ impl AsyncTryFromWithProxy<nero_extensions::types::Video> for Video {
         }?;
 
         Ok(Self {
+            id: video.id,
             url,
             server: video.server,
             resolution: video.resolution,
+            proxied: true,
+            is_live,
+            next_episode_id: video.next_episode_id,
+            source_id: None,
         })
     }
 }
 
 #[derive(Debug, Serialize)]
-#[serde(rename_all = "camelCase")]
+#[cfg_attr(not(feature = "snake-case"), serde(rename_all = "camelCase"))]
 pub struct Filter {
     pub id: String,
     pub display_name: String,
@@ -183,7 +312,7 @@ impl From<nero_extensions::types::Filter> for Filter {
 }
 
 #[derive(Debug, Serialize)]
-#[serde(rename_all = "camelCase")]
+#[cfg_attr(not(feature = "snake-case"), serde(rename_all = "camelCase"))]
 pub struct FilterCategory {
     pub id: String,
     pub display_name: String,
@@ -200,7 +329,7 @@ impl From<nero_extensions::types::FilterCategory> for FilterCategory {
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct SearchFilter {
     pub id: String,
     pub values: Vec<String>,
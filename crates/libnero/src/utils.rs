@@ -1,4 +1,7 @@
 use nero_media_proxy::MediaProxy;
+use wasm_metadata::Producers;
+
+use crate::ExtensionMetadata;
 
 pub trait AsyncTryFromWithProxy<T>: Sized {
     async fn async_try_from_with_proxy(value: T, proxy: &MediaProxy) -> anyhow::Result<Self>;
@@ -16,3 +19,24 @@ where
         U::async_try_from_with_proxy(self, proxy).await
     }
 }
+
+/// Copies extension metadata, which doesn't implement `Clone`.
+pub fn clone_metadata(metadata: &ExtensionMetadata) -> ExtensionMetadata {
+    ExtensionMetadata {
+        name: metadata.name.clone(),
+        producers: metadata.producers.as_ref().map(|producers| {
+            let mut copy = Producers::empty();
+            copy.merge(producers);
+            copy
+        }),
+        authors: metadata.authors.clone(),
+        description: metadata.description.clone(),
+        licenses: metadata.licenses.clone(),
+        source: metadata.source.clone(),
+        homepage: metadata.homepage.clone(),
+        revision: metadata.revision.clone(),
+        version: metadata.version.clone(),
+        range: metadata.range.clone(),
+        dependencies: metadata.dependencies.clone(),
+    }
+}
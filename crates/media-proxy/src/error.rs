@@ -18,12 +18,39 @@ pub enum Error {
     #[error("Torrent support is disabled")]
     TorrentSupportDisabled,
 
+    #[cfg(feature = "torrent")]
+    #[error("Torrent was not ready in time")]
+    TorrentNotReady,
+
+    #[cfg(feature = "torrent")]
+    #[error("Torrent data is not available yet")]
+    TorrentDataUnavailable,
+
+    #[cfg(feature = "torrent")]
+    #[error("Torrent backend is not responding")]
+    TorrentBackendUnavailable,
+
     #[cfg(feature = "torrent")]
     #[error("Torrent error: {0}")]
     TorrentBackend(#[from] anyhow::Error),
 
     #[error("Invalid resource kind")]
     InvalidResourceKind,
+
+    #[error("Request was cancelled")]
+    Cancelled,
+
+    #[cfg(feature = "transcode")]
+    #[error("Unsupported transcode target: {0}")]
+    UnsupportedTranscodeTarget(String),
+
+    #[cfg(feature = "transcode")]
+    #[error("Could not start transcoding: {0}")]
+    Transcode(std::io::Error),
+
+    #[cfg(feature = "imaging")]
+    #[error("Image processing error: {0}")]
+    Image(#[from] image::ImageError),
 }
 
 impl IntoResponse for Error {
@@ -44,6 +71,20 @@ impl IntoResponse for Error {
                 StatusCode::BAD_REQUEST
             }
             #[cfg(feature = "torrent")]
+            Error::TorrentNotReady => StatusCode::GATEWAY_TIMEOUT,
+            #[cfg(feature = "torrent")]
+            Error::TorrentDataUnavailable => {
+                // Hint the player to retry shortly, the data is likely being downloaded.
+                return (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    [(http::header::RETRY_AFTER, "1")],
+                    self.to_string(),
+                )
+                    .into_response();
+            }
+            #[cfg(feature = "torrent")]
+            Error::TorrentBackendUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            #[cfg(feature = "torrent")]
             Error::TorrentBackend(e) => {
                 error!("Torrent backend error: {:#}", e);
                 StatusCode::INTERNAL_SERVER_ERROR
@@ -52,6 +93,19 @@ impl IntoResponse for Error {
                 error!("Invalid resource kind: {:#}", self);
                 StatusCode::BAD_REQUEST
             }
+            Error::Cancelled => StatusCode::GONE,
+            #[cfg(feature = "transcode")]
+            Error::UnsupportedTranscodeTarget(_) => StatusCode::BAD_REQUEST,
+            #[cfg(feature = "transcode")]
+            Error::Transcode(e) => {
+                error!("Could not start ffmpeg: {:#}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            #[cfg(feature = "imaging")]
+            Error::Image(e) => {
+                error!("Image processing error: {:#}", e);
+                StatusCode::BAD_GATEWAY
+            }
         };
 
         (status, self.to_string()).into_response()
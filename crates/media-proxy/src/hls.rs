@@ -0,0 +1,76 @@
+//! Just enough HLS playlist parsing to tell live streams from VOD.
+
+use http::Uri;
+
+/// Whether the URI points at an HLS playlist, judging by its extension.
+pub(crate) fn is_playlist_uri(uri: &Uri) -> bool {
+    uri.path()
+        .rsplit_once('.')
+        .is_some_and(|(_, extension)| extension.eq_ignore_ascii_case("m3u8"))
+}
+
+/// Returns the URI of the first variant listed in a master playlist, `None` for a media
+/// playlist.
+pub(crate) fn first_variant(playlist: &str) -> Option<&str> {
+    let mut lines = playlist.lines().map(str::trim);
+    lines.find(|line| line.starts_with("#EXT-X-STREAM-INF"))?;
+    lines.find(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+/// A media playlist without `#EXT-X-ENDLIST` is still being appended to, i.e. live.
+pub(crate) fn is_live(media_playlist: &str) -> bool {
+    !media_playlist
+        .lines()
+        .any(|line| line.trim() == "#EXT-X-ENDLIST")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{first_variant, is_live, is_playlist_uri};
+
+    const MASTER: &str = "#EXTM3U
+#EXT-X-STREAM-INF:BANDWIDTH=1280000,RESOLUTION=1280x720
+720p/index.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=2560000,RESOLUTION=1920x1080
+1080p/index.m3u8
+";
+
+    const VOD: &str = "#EXTM3U
+#EXT-X-TARGETDURATION:10
+#EXTINF:10.0,
+segment0.ts
+#EXTINF:10.0,
+segment1.ts
+#EXT-X-ENDLIST
+";
+
+    const LIVE: &str = "#EXTM3U
+#EXT-X-TARGETDURATION:6
+#EXT-X-MEDIA-SEQUENCE:2680
+#EXTINF:6.0,
+segment2680.ts
+";
+
+    #[test]
+    fn detects_playlist_uris() {
+        assert!(is_playlist_uri(
+            &"http://a/live/index.m3u8".parse().unwrap()
+        ));
+        assert!(is_playlist_uri(
+            &"http://a/index.M3U8?token=1".parse().unwrap()
+        ));
+        assert!(!is_playlist_uri(&"http://a/video.mp4".parse().unwrap()));
+    }
+
+    #[test]
+    fn master_playlist_yields_first_variant() {
+        assert_eq!(first_variant(MASTER), Some("720p/index.m3u8"));
+        assert_eq!(first_variant(VOD), None);
+    }
+
+    #[test]
+    fn endlist_marks_vod() {
+        assert!(!is_live(VOD));
+        assert!(is_live(LIVE));
+    }
+}
@@ -0,0 +1,397 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    hash::{DefaultHasher, Hash, Hasher},
+    io::{self, Cursor},
+    str::FromStr,
+};
+
+use axum::{body::Body, response::Response};
+use bytes::Bytes;
+use http::{
+    HeaderMap, StatusCode,
+    header::{ACCEPT_RANGES, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_NONE_MATCH, IF_RANGE, RANGE},
+};
+#[cfg(feature = "image-transcode")]
+use image::{DynamicImage, ImageFormat};
+use image::{ImageError, ImageResult};
+use mime::Mime;
+use tokio::sync::Mutex;
+use url::Url;
+
+use crate::stats::{CacheCounters, CacheStats};
+
+use crate::utils::{etag_matches, parse_byte_range};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ImageSize {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl ImageSize {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self { width, height }
+    }
+}
+
+impl fmt::Display for ImageSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{}", self.width, self.height)
+    }
+}
+
+impl FromStr for ImageSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (width, height) = s
+            .split_once('x')
+            .ok_or_else(|| anyhow::anyhow!("invalid image size '{s}'"))?;
+
+        Ok(Self::new(width.parse()?, height.parse()?))
+    }
+}
+
+/// URLs returned when registering an image together with its resized variants.
+#[derive(Debug, Clone)]
+pub struct ImageVariants {
+    pub original: Url,
+    pub sizes: HashMap<ImageSize, Url>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CachedImage {
+    pub bytes: Bytes,
+    pub mime: Mime,
+    pub etag: String,
+}
+
+impl CachedImage {
+    pub fn new(bytes: Bytes, mime: Mime) -> Self {
+        let mut hasher = DefaultHasher::new();
+        bytes.hash(&mut hasher);
+        let etag = format!("\"{:016x}\"", hasher.finish());
+
+        Self { bytes, mime, etag }
+    }
+
+    /// Builds the response to a request, serving byte ranges straight from the cached bytes and
+    /// a `304` when the client's copy is current.
+    pub fn respond(&self, request_headers: &HeaderMap) -> Response {
+        let total_len = self.bytes.len() as u64;
+        let builder = Response::builder()
+            .header(CONTENT_TYPE, self.mime.as_ref())
+            .header(ETAG, &self.etag)
+            .header(ACCEPT_RANGES, "bytes");
+
+        if request_headers
+            .get(IF_NONE_MATCH)
+            .is_some_and(|v| etag_matches(v, &self.etag))
+        {
+            return builder
+                .status(StatusCode::NOT_MODIFIED)
+                .body(Body::empty())
+                .unwrap();
+        }
+
+        // A mismatching `If-Range` means the client's partial copy is stale, send everything.
+        let range = request_headers
+            .get(RANGE)
+            .and_then(|v| v.to_str().ok())
+            .filter(|_| {
+                request_headers
+                    .get(IF_RANGE)
+                    .is_none_or(|v| v == self.etag.as_str())
+            });
+
+        let Some(range) = range else {
+            return builder.body(Body::from(self.bytes.clone())).unwrap();
+        };
+
+        match parse_byte_range(range, total_len) {
+            Some(range) => builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(
+                    CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", range.start, range.end - 1, total_len),
+                )
+                .body(Body::from(
+                    self.bytes.slice(range.start as usize..range.end as usize),
+                ))
+                .unwrap(),
+            None => builder
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(CONTENT_RANGE, format!("bytes */{total_len}"))
+                .body(Body::empty())
+                .unwrap(),
+        }
+    }
+}
+
+/// Processed images keyed by the resource they were produced from, up to a total size past
+/// which the least recently used ones are dropped.
+pub struct ImageCache {
+    entries: Mutex<Entries>,
+    max_bytes: u64,
+    counters: CacheCounters,
+}
+
+#[derive(Default)]
+struct Entries {
+    images: HashMap<String, (CachedImage, u64)>,
+    /// Keys by the tick they were last used at, least recent first.
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+    bytes: u64,
+}
+
+impl Entries {
+    fn touch(&mut self, key: &str) -> Option<CachedImage> {
+        self.tick += 1;
+        let (image, used) = self.images.get_mut(key)?;
+        self.recency.remove(used);
+        *used = self.tick;
+        self.recency.insert(self.tick, key.to_owned());
+        Some(image.clone())
+    }
+
+    fn insert(&mut self, key: String, image: CachedImage) {
+        self.remove(&key);
+        self.tick += 1;
+        self.bytes += image.bytes.len() as u64;
+        self.recency.insert(self.tick, key.clone());
+        self.images.insert(key, (image, self.tick));
+    }
+
+    fn remove(&mut self, key: &str) -> bool {
+        let Some((image, used)) = self.images.remove(key) else {
+            return false;
+        };
+        self.recency.remove(&used);
+        self.bytes -= image.bytes.len() as u64;
+        true
+    }
+
+    /// Drops the least recently used images until they fit in `max_bytes`, returning how many
+    /// were dropped.
+    fn shrink_to(&mut self, max_bytes: u64) -> u64 {
+        let mut evicted = 0;
+        while self.bytes > max_bytes
+            && let Some((_, key)) = self.recency.pop_first()
+        {
+            if let Some((image, _)) = self.images.remove(&key) {
+                self.bytes -= image.bytes.len() as u64;
+                evicted += 1;
+            }
+        }
+        evicted
+    }
+}
+
+impl ImageCache {
+    pub fn new(max_bytes: u64) -> Self {
+        Self {
+            entries: Mutex::default(),
+            max_bytes,
+            counters: CacheCounters::default(),
+        }
+    }
+
+    pub async fn get(&self, key: &str) -> Option<CachedImage> {
+        let image = self.entries.lock().await.touch(key);
+        self.counters.record_lookup(image.is_some());
+        image
+    }
+
+    /// Caches an image, dropping the least recently used ones to make room for it. Images
+    /// larger than the whole cache aren't kept.
+    pub async fn insert(&self, key: String, image: CachedImage) {
+        if image.bytes.len() as u64 > self.max_bytes {
+            return;
+        }
+
+        let mut entries = self.entries.lock().await;
+        entries.insert(key, image);
+        let evicted = entries.shrink_to(self.max_bytes);
+        self.counters.record_evictions(evicted);
+    }
+
+    /// Drops an image whose resource is gone.
+    pub async fn remove(&self, key: &str) {
+        if self.entries.lock().await.remove(key) {
+            self.counters.record_evictions(1);
+        }
+    }
+
+    /// Drops every image produced from `resource_id`, whatever its size or format. Returns
+    /// how many were dropped.
+    pub async fn remove_resource(&self, resource_id: &str) -> usize {
+        let mut entries = self.entries.lock().await;
+        let keys = entries
+            .images
+            .keys()
+            .filter(|key| {
+                let rest = key.strip_prefix(resource_id);
+                rest.is_some_and(|rest| rest.is_empty() || rest.starts_with(['@', '/']))
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        for key in &keys {
+            entries.remove(key);
+        }
+        keys.len()
+    }
+
+    pub async fn clear(&self) {
+        *self.entries.lock().await = Entries::default();
+    }
+
+    pub async fn stats(&self) -> CacheStats {
+        self.counters
+            .snapshot(self.entries.lock().await.images.len())
+    }
+}
+
+/// Runs image processing on the blocking thread pool. A task that panicked or was cancelled is
+/// reported as an image error, so callers fall back as they do for any other failure.
+pub async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> ImageResult<T> + Send + 'static,
+) -> ImageResult<T> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .unwrap_or_else(|err| Err(ImageError::IoError(io::Error::other(err))))
+}
+
+/// Reads an image's dimensions from its header, which may be all `bytes` contains.
+pub fn dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    image::ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+/// Resizes an encoded image to fit within `size`, preserving its aspect ratio and format.
+pub fn resize(bytes: &[u8], size: ImageSize) -> ImageResult<CachedImage> {
+    let format = image::guess_format(bytes)?;
+    let resized =
+        image::load_from_memory_with_format(bytes, format)?.thumbnail(size.width, size.height);
+
+    let mut buf = Cursor::new(Vec::new());
+    resized.write_to(&mut buf, format)?;
+
+    let mime = format
+        .to_mime_type()
+        .parse()
+        .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+
+    Ok(CachedImage::new(Bytes::from(buf.into_inner()), mime))
+}
+
+/// Formats images can be transcoded to, in order of preference.
+#[cfg(feature = "image-transcode")]
+const TRANSCODE_FORMATS: [ImageFormat; 2] = [ImageFormat::Avif, ImageFormat::WebP];
+
+/// Picks the preferred transcode format explicitly listed in an `Accept` header. AVIF is only
+/// considered when `avif` is set, its encoding being far slower than WebP's.
+///
+/// Wildcards are ignored so clients only receive a modern format they asked for by name.
+#[cfg(feature = "image-transcode")]
+pub fn preferred_format(accept: &str, avif: bool) -> Option<ImageFormat> {
+    let accepted = accept
+        .split(',')
+        .filter_map(|range| {
+            let mut params = range.split(';').map(str::trim);
+            let mime = params.next()?;
+            let rejected = params.any(|p| matches!(p, "q=0" | "q=0.0" | "q=0.00" | "q=0.000"));
+            (!rejected).then_some(mime)
+        })
+        .collect::<Vec<_>>();
+
+    TRANSCODE_FORMATS
+        .into_iter()
+        .filter(|format| avif || *format != ImageFormat::Avif)
+        .find(|format| accepted.contains(&format.to_mime_type()))
+}
+
+#[cfg(feature = "image-transcode")]
+pub fn transcode_key(resource_id: &str, format: ImageFormat) -> String {
+    format!("{resource_id}@{}", format.to_mime_type())
+}
+
+/// Speed preset AVIF is encoded with, from 1 (slowest) to 10 (fastest).
+#[cfg(feature = "image-transcode")]
+const AVIF_SPEED: u8 = 10;
+
+#[cfg(feature = "image-transcode")]
+const AVIF_QUALITY: u8 = 80;
+
+/// Re-encodes an image into `format`.
+#[cfg(feature = "image-transcode")]
+pub fn transcode(bytes: &[u8], format: ImageFormat) -> ImageResult<CachedImage> {
+    let image = DynamicImage::ImageRgba8(image::load_from_memory(bytes)?.to_rgba8());
+
+    let mut buf = Cursor::new(Vec::new());
+    if format == ImageFormat::Avif {
+        let encoder = image::codecs::avif::AvifEncoder::new_with_speed_quality(
+            &mut buf,
+            AVIF_SPEED,
+            AVIF_QUALITY,
+        );
+        image.write_with_encoder(encoder)?;
+    } else {
+        image.write_to(&mut buf, format)?;
+    }
+
+    let mime = format
+        .to_mime_type()
+        .parse()
+        .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+
+    Ok(CachedImage::new(Bytes::from(buf.into_inner()), mime))
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::{CachedImage, ImageCache};
+
+    fn image(len: usize) -> CachedImage {
+        CachedImage::new(Bytes::from(vec![0; len]), mime::IMAGE_PNG)
+    }
+
+    #[tokio::test]
+    async fn least_recently_used_images_are_evicted_past_the_size_limit() {
+        let cache = ImageCache::new(100);
+        cache.insert("a".into(), image(40)).await;
+        cache.insert("b".into(), image(40)).await;
+        assert!(cache.get("a").await.is_some());
+
+        cache.insert("c".into(), image(40)).await;
+        assert!(cache.get("b").await.is_none());
+        assert!(cache.get("a").await.is_some());
+        assert!(cache.get("c").await.is_some());
+
+        cache.insert("too-large".into(), image(101)).await;
+        assert!(cache.get("too-large").await.is_none());
+
+        let stats = cache.stats().await;
+        assert_eq!(stats.evictions, 1);
+        assert_eq!(stats.current_size, 2);
+    }
+
+    #[cfg(feature = "image-transcode")]
+    #[test]
+    fn avif_is_only_preferred_when_enabled() {
+        use image::ImageFormat;
+
+        use super::preferred_format;
+
+        let accept = "image/avif,image/webp,image/*;q=0.8";
+        assert_eq!(preferred_format(accept, false), Some(ImageFormat::WebP));
+        assert_eq!(preferred_format(accept, true), Some(ImageFormat::Avif));
+        assert_eq!(preferred_format("image/avif", false), None);
+    }
+}
@@ -0,0 +1,141 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use tokio_util::sync::CancellationToken;
+
+type Tokens = Arc<Mutex<HashMap<String, (u64, CancellationToken)>>>;
+
+/// Tracks the upstream fetches currently being proxied so they can be cancelled by resource id.
+#[derive(Default)]
+pub(crate) struct InflightRequests {
+    next_id: AtomicU64,
+    tokens: Tokens,
+}
+
+impl InflightRequests {
+    /// Registers a fetch for `id`. It stays registered until the returned guard is dropped.
+    pub fn register(&self, id: &str) -> InflightGuard {
+        let generation = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let token = CancellationToken::new();
+
+        let previous = self
+            .tokens
+            .lock()
+            .unwrap()
+            .insert(id.to_owned(), (generation, token.clone()));
+        if let Some((_, previous)) = previous {
+            previous.cancel();
+        }
+
+        InflightGuard {
+            id: id.to_owned(),
+            generation,
+            token,
+            tokens: self.tokens.clone(),
+        }
+    }
+
+    /// Cancels the fetch registered for `id`, returning whether one was in flight.
+    pub fn cancel(&self, id: &str) -> bool {
+        match self.tokens.lock().unwrap().remove(id) {
+            Some((_, token)) => {
+                token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Cancels every fetch in flight.
+    pub fn cancel_all(&self) {
+        for (_, (_, token)) in self.tokens.lock().unwrap().drain() {
+            token.cancel();
+        }
+    }
+}
+
+pub(crate) struct InflightGuard {
+    id: String,
+    generation: u64,
+    token: CancellationToken,
+    tokens: Tokens,
+}
+
+impl InflightGuard {
+    pub fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+
+    /// Resolves once the fetch is cancelled, keeping it registered for as long as the future is
+    /// alive. Meant to bound a response body stream.
+    pub async fn cancelled(self) {
+        self.token.cancelled().await;
+    }
+}
+
+impl Drop for InflightGuard {
+    fn drop(&mut self) {
+        let mut tokens = self.tokens.lock().unwrap();
+        // A newer fetch may have taken over the id, only unregister our own.
+        if tokens
+            .get(&self.id)
+            .is_some_and(|(generation, _)| *generation == self.generation)
+        {
+            tokens.remove(&self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cancel_fires_token_and_unregisters() {
+        let inflight = InflightRequests::default();
+        let guard = inflight.register("a");
+
+        assert!(inflight.cancel("a"));
+        assert!(guard.token().is_cancelled());
+        assert!(!inflight.cancel("a"));
+    }
+
+    #[test]
+    fn dropping_guard_unregisters() {
+        let inflight = InflightRequests::default();
+        drop(inflight.register("a"));
+
+        assert!(!inflight.cancel("a"));
+    }
+
+    #[test]
+    fn stale_guard_keeps_newer_registration() {
+        let inflight = InflightRequests::default();
+        let first = inflight.register("a");
+        let second = inflight.register("a");
+
+        assert!(first.token().is_cancelled());
+        drop(first);
+
+        assert!(inflight.cancel("a"));
+        assert!(second.token().is_cancelled());
+    }
+
+    #[test]
+    fn cancel_all_fires_every_token() {
+        let inflight = InflightRequests::default();
+        let a = inflight.register("a");
+        let b = inflight.register("b");
+
+        inflight.cancel_all();
+
+        assert!(a.token().is_cancelled());
+        assert!(b.token().is_cancelled());
+        assert!(!inflight.cancel("a"));
+    }
+}
@@ -1,50 +1,182 @@
 mod error;
-mod mime;
+mod hls;
+#[cfg(feature = "imaging")]
+pub mod imaging;
+mod inflight;
+pub mod mime;
 pub mod resources;
 mod routes;
+pub mod stats;
+mod throttle;
 #[cfg(feature = "torrent")]
 pub mod torrent;
+#[cfg(feature = "transcode")]
+pub mod transcode;
 pub mod utils;
 
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
 
 use axum::{Router, routing::get};
 use bytes::Bytes;
+use http::uri::Scheme;
 use tokio::sync::RwLock;
+use url::Url;
 
 #[cfg(feature = "torrent")]
 use crate::torrent::Torrent;
 use crate::{
-    resources::{Resource, ResourceStore, ResourceStoreConfig},
+    inflight::InflightRequests,
+    resources::{Resource, ResourceId, ResourceStore, ResourceStoreConfig, StoredResource},
     routes::{handle_image_request, handle_video_request},
 };
 
 pub type HttpRequest = http::Request<Option<Bytes>>;
 
+#[cfg(feature = "torrent")]
+pub const DEFAULT_TORRENT_READY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Total size of the processed images kept in memory when
+/// [`MediaProxyConfig::image_cache_max_bytes`] is unset.
+#[cfg(feature = "imaging")]
+pub const DEFAULT_IMAGE_CACHE_MAX_BYTES: u64 = 64 * 1024 * 1024;
+
 #[derive(Default)]
 pub struct MediaProxyConfig {
     pub resource_store: ResourceStoreConfig,
+    /// Stages run by the default MIME detector. At least one must be enabled.
+    pub mime_detection: mime::MimeDetectionConfig,
     #[cfg(feature = "torrent")]
     pub torrent_backend: Option<Arc<dyn torrent::TorrentBackend>>,
     #[cfg(feature = "torrent")]
     pub torrent_file_selector: Option<Arc<dyn torrent::TorrentFileSelector>>,
+    /// Extra headers added to torrent stream responses. `Content-Range`, `Content-Length` and
+    /// `Accept-Ranges` are never overridden.
+    #[cfg(feature = "torrent")]
+    pub torrent_stream_headers: http::HeaderMap,
+    /// Maximum number of files of a torrent listed in its m3u and downloaded, the rest are
+    /// dropped with a warning. Unlimited when unset.
+    #[cfg(feature = "torrent")]
+    pub torrent_max_files: Option<usize>,
+    /// How long a stream request waits for its torrent to be ready before failing with a
+    /// `504`. Defaults to [`DEFAULT_TORRENT_READY_TIMEOUT`].
+    #[cfg(feature = "torrent")]
+    pub torrent_ready_timeout: Option<std::time::Duration>,
+    /// How seeks into data that isn't downloaded yet are handled. Blocks by default.
+    #[cfg(feature = "torrent")]
+    pub torrent_unavailable_data: torrent::UnavailableDataPolicy,
+    /// Lists streams in the torrent m3u by URLs relative to the playlist itself instead of
+    /// absolute ones, so it works whichever host or scheme it was fetched through.
+    #[cfg(feature = "torrent")]
+    pub torrent_relative_stream_urls: bool,
+    /// Lets several torrents stream at once, e.g. for picture-in-picture. By default starting a
+    /// torrent cancels the active one, otherwise torrents run until
+    /// [`MediaProxy::stop_torrent`] is called.
+    #[cfg(feature = "torrent")]
+    pub torrent_concurrent_playback: bool,
+    /// Images larger than this on either side are downscaled to fit before being served.
+    #[cfg(feature = "image-resize")]
+    pub image_max_dimension: Option<u32>,
+    /// Lets images be transcoded to AVIF for clients accepting it, otherwise they get WebP.
+    /// AVIF is smaller but takes far more CPU to encode, even with the fastest preset used here.
+    #[cfg(feature = "image-transcode")]
+    pub image_transcode_avif: bool,
+    /// Total size of the resized and transcoded images kept in memory, the least recently
+    /// used ones are dropped past it. Defaults to [`DEFAULT_IMAGE_CACHE_MAX_BYTES`].
+    #[cfg(feature = "imaging")]
+    pub image_cache_max_bytes: Option<u64>,
+    /// The `ffmpeg` binary used to transcode streams, looked up in `PATH` when unset.
+    #[cfg(feature = "transcode")]
+    pub ffmpeg_path: Option<std::path::PathBuf>,
+    /// Base of the URLs handed out for registered resources, e.g. `https://media.example.com/`
+    /// when the proxy sits behind a TLS terminator. Defaults to `http://` and the address the
+    /// proxy listens on. Routes are still served at the root of the listening address.
+    pub public_base_url: Option<Url>,
+    /// Credentials added to outbound requests by host, unless the extension already set an
+    /// `Authorization` header.
+    pub host_credentials: HashMap<String, utils::Credential>,
+    /// Hostnames resolved to a fixed address by the client built in
+    /// [`MediaProxy::with_config`], bypassing DNS. Ignored when a custom client is passed to
+    /// [`MediaProxy::new`].
+    pub dns_overrides: HashMap<String, IpAddr>,
+    /// Bandwidth each video and torrent stream is read at, in bytes per second. Unlimited when
+    /// unset.
+    pub max_bytes_per_sec: Option<u64>,
+}
+
+/// Statistics of the caches kept by a [`MediaProxy`].
+#[derive(Debug, Clone, Copy)]
+pub struct MediaProxyCacheStats {
+    /// Registered video and image requests.
+    pub resources: stats::CacheStats,
+    /// Processed images.
+    #[cfg(feature = "imaging")]
+    pub images: stats::CacheStats,
+}
+
+/// The video the proxy is currently streaming.
+#[derive(Debug, Clone)]
+pub enum CurrentVideo {
+    /// A video proxied over HTTP, identified by its upstream URI.
+    Http(http::Uri),
+    /// A torrent along with the stream URL of each of its files, in the same order.
+    #[cfg(feature = "torrent")]
+    Torrent {
+        torrent: Torrent,
+        stream_urls: Vec<Url>,
+    },
 }
 
 pub struct ServerState {
     #[cfg(feature = "torrent")]
-    addr: SocketAddr,
+    base_url: Url,
 
     http_client: reqwest::Client,
+    host_credentials: Arc<HashMap<String, utils::Credential>>,
     #[cfg(feature = "torrent")]
     torrent_backend: Option<Arc<dyn torrent::TorrentBackend>>,
     #[cfg(feature = "torrent")]
     torrent_file_selector: Option<Arc<dyn torrent::TorrentFileSelector>>,
+    #[cfg(feature = "torrent")]
+    torrent_stream_headers: http::HeaderMap,
+    #[cfg(feature = "torrent")]
+    torrent_max_files: Option<usize>,
+    #[cfg(feature = "torrent")]
+    torrent_ready_timeout: std::time::Duration,
+    #[cfg(feature = "torrent")]
+    torrent_unavailable_data: torrent::UnavailableDataPolicy,
+    #[cfg(feature = "torrent")]
+    torrent_relative_stream_urls: bool,
+    #[cfg(feature = "torrent")]
+    torrent_concurrent_playback: bool,
 
     resource_store: ResourceStore,
+    #[cfg(feature = "imaging")]
+    image_cache: imaging::ImageCache,
+    #[cfg(feature = "image-resize")]
+    image_max_dimension: Option<u32>,
+    #[cfg(feature = "image-transcode")]
+    image_transcode_avif: bool,
+    #[cfg(feature = "transcode")]
+    ffmpeg_path: std::path::PathBuf,
+    inflight: InflightRequests,
+    max_bytes_per_sec: Option<u64>,
+    /// Validators of served images, their resources being gone once fetched.
+    image_validators: routes::ImageValidators,
+    metrics_exporter: std::sync::RwLock<Option<Arc<dyn stats::MetricsExporter>>>,
 
-    current_video: RwLock<Option<Resource>>,
+    /// The video last requested through the video route, under its resource id, so seeks can
+    /// fetch it again after its registration was consumed.
+    current_video: RwLock<Option<(String, StoredResource)>>,
+    #[cfg(feature = "torrent")]
+    /// Added torrents, the most recently started one last.
+    active_torrents: RwLock<Vec<Torrent>>,
     #[cfg(feature = "torrent")]
-    current_torrent: RwLock<Option<Torrent>>,
+    /// Torrents listed through their m3u, by resource id.
+    pending_torrents: RwLock<HashMap<String, routes::PendingTorrent>>,
 }
 
 pub struct MediaProxy {
@@ -52,36 +184,450 @@ pub struct MediaProxy {
 }
 
 impl MediaProxy {
-    pub fn new(addr: SocketAddr, http_client: reqwest::Client, config: MediaProxyConfig) -> Self {
+    /// Fails when the configuration is invalid, e.g. every MIME detection stage is disabled.
+    pub fn new(
+        addr: SocketAddr,
+        http_client: reqwest::Client,
+        mut config: MediaProxyConfig,
+    ) -> anyhow::Result<Self> {
+        let host_credentials = Arc::new(std::mem::take(&mut config.host_credentials));
+        let mime_detector = mime::DefaultMimeDetector::new(config.mime_detection)?
+            .with_host_credentials(host_credentials.clone());
+        config
+            .resource_store
+            .mime_detector
+            .get_or_insert_with(|| Arc::new(mime_detector));
+
+        let base_url = base_url(addr, config.public_base_url.take());
         let state = ServerState {
             #[cfg(feature = "torrent")]
-            addr,
+            base_url: base_url.clone(),
             http_client: http_client.clone(),
+            host_credentials: host_credentials.clone(),
 
             #[cfg(feature = "torrent")]
             torrent_backend: config.torrent_backend,
             #[cfg(feature = "torrent")]
             torrent_file_selector: config.torrent_file_selector,
+            #[cfg(feature = "torrent")]
+            torrent_stream_headers: config.torrent_stream_headers,
+            #[cfg(feature = "torrent")]
+            torrent_max_files: config.torrent_max_files,
+            #[cfg(feature = "torrent")]
+            torrent_ready_timeout: config
+                .torrent_ready_timeout
+                .unwrap_or(DEFAULT_TORRENT_READY_TIMEOUT),
+            #[cfg(feature = "torrent")]
+            torrent_unavailable_data: config.torrent_unavailable_data,
+            #[cfg(feature = "torrent")]
+            torrent_relative_stream_urls: config.torrent_relative_stream_urls,
+            #[cfg(feature = "torrent")]
+            torrent_concurrent_playback: config.torrent_concurrent_playback,
 
-            resource_store: ResourceStore::new(addr, http_client, config.resource_store),
+            resource_store: ResourceStore::new(
+                base_url,
+                http_client,
+                host_credentials,
+                config.resource_store,
+            ),
+            #[cfg(feature = "imaging")]
+            image_cache: imaging::ImageCache::new(
+                config
+                    .image_cache_max_bytes
+                    .unwrap_or(DEFAULT_IMAGE_CACHE_MAX_BYTES),
+            ),
+            #[cfg(feature = "image-resize")]
+            image_max_dimension: config.image_max_dimension,
+            #[cfg(feature = "image-transcode")]
+            image_transcode_avif: config.image_transcode_avif,
+            #[cfg(feature = "transcode")]
+            ffmpeg_path: config.ffmpeg_path.unwrap_or_else(|| "ffmpeg".into()),
+            inflight: InflightRequests::default(),
+            max_bytes_per_sec: config.max_bytes_per_sec,
+            image_validators: routes::ImageValidators::default(),
+            metrics_exporter: std::sync::RwLock::default(),
             current_video: RwLock::new(None),
             #[cfg(feature = "torrent")]
-            current_torrent: RwLock::new(None),
+            active_torrents: RwLock::default(),
+            #[cfg(feature = "torrent")]
+            pending_torrents: RwLock::default(),
         };
 
-        Self {
+        Ok(Self {
             state: Arc::new(state),
+        })
+    }
+
+    /// Creates a proxy with its own HTTP client, built from the redirect policy in
+    /// [`utils::redirect_policy`] and the configured DNS overrides.
+    pub fn with_config(addr: SocketAddr, config: MediaProxyConfig) -> anyhow::Result<Self> {
+        let mut builder = reqwest::Client::builder()
+            .redirect(utils::redirect_policy(utils::DEFAULT_MAX_REDIRECTS));
+        for (host, ip) in &config.dns_overrides {
+            // Port 0 keeps the port of the requested URL.
+            builder = builder.resolve(host, SocketAddr::new(*ip, 0));
         }
+
+        Self::new(addr, builder.build()?, config)
+    }
+
+    /// Serves the metrics of `exporter` at `/metrics`, which answers `404` until one is set.
+    pub fn set_metrics_exporter(&self, exporter: Arc<dyn stats::MetricsExporter>) {
+        *self.state.metrics_exporter.write().unwrap() = Some(exporter);
     }
 
     pub fn resource_store(&self) -> &ResourceStore {
         &self.state.resource_store
     }
 
+    /// Drops the image registered under `url`, a URL returned when it was registered, along
+    /// with every processed copy of it, so the next registration fetches it anew. Returns
+    /// whether anything was dropped.
+    pub async fn invalidate_image(&self, url: &Url) -> bool {
+        let base_url = self.state.resource_store.base_url();
+        let Some(key) = resource_key_from_url(url, base_url, "image") else {
+            return false;
+        };
+
+        let removed = self.state.resource_store.remove(&key).await.is_some();
+        #[cfg(feature = "imaging")]
+        let removed = self.state.image_cache.remove_resource(&key).await > 0 || removed;
+        removed
+    }
+
+    /// Drops the video registered under `url`, a URL returned when it was registered, and
+    /// forgets it as the current video so seeks no longer fetch it. Returns whether anything
+    /// was dropped.
+    pub async fn invalidate_video(&self, url: &Url) -> bool {
+        let base_url = self.state.resource_store.base_url();
+        let Some(key) = resource_key_from_url(url, base_url, "video") else {
+            return false;
+        };
+
+        let removed = self.state.resource_store.remove(&key).await.is_some();
+        let mut current_video = self.state.current_video.write().await;
+        if current_video.as_ref().is_some_and(|(id, _)| *id == key) {
+            current_video.take();
+            return true;
+        }
+        removed
+    }
+
+    /// Drops every registered resource and processed image.
+    pub async fn clear_caches(&self) -> anyhow::Result<()> {
+        #[cfg(feature = "imaging")]
+        self.state.image_cache.clear().await;
+        self.state.resource_store.clear().await
+    }
+
+    /// Returns hit, miss and eviction counts of the proxy caches, e.g. to be logged
+    /// periodically.
+    pub async fn cache_stats(&self) -> MediaProxyCacheStats {
+        MediaProxyCacheStats {
+            resources: self.state.resource_store.stats().await,
+            #[cfg(feature = "imaging")]
+            images: self.state.image_cache.stats().await,
+        }
+    }
+
+    /// Cancels the proxied fetch of `resource_id`, whether it is still pending or already
+    /// streaming. Returns `false` if there was nothing to cancel.
+    pub async fn cancel(&self, resource_id: &str) -> bool {
+        let pending = self
+            .state
+            .resource_store
+            .remove(resource_id)
+            .await
+            .is_some();
+        self.state.inflight.cancel(resource_id) || pending
+    }
+
+    /// Returns what is currently being streamed, so a client can restore its state or re-attach
+    /// to an ongoing torrent stream.
+    pub async fn current_video(&self) -> Option<CurrentVideo> {
+        if let Some((
+            _,
+            StoredResource {
+                resource: Resource::Http(request),
+                ..
+            },
+        )) = self.state.current_video.read().await.as_ref()
+        {
+            return Some(CurrentVideo::Http(request.uri().clone()));
+        }
+
+        #[cfg(feature = "torrent")]
+        if let Some(torrent) = self.state.active_torrents.read().await.last().cloned() {
+            let stream_urls = torrent
+                .files
+                .iter()
+                .map(|file| {
+                    self.state
+                        .base_url
+                        .join(&format!("torrent/{}/stream/{}", torrent.id, file.index))
+                })
+                .collect::<Result<_, _>>()
+                .ok()?;
+
+            return Some(CurrentVideo::Torrent {
+                torrent,
+                stream_urls,
+            });
+        }
+
+        None
+    }
+
+    /// Resolves which files of a torrent would be downloaded, without adding it to the backend.
+    ///
+    /// Every file is returned when no [`torrent::TorrentFileSelector`] is configured.
+    #[cfg(feature = "torrent")]
+    pub async fn preview_torrent_files(
+        &self,
+        source: &torrent::TorrentSource,
+    ) -> anyhow::Result<Vec<torrent::TorrentFile>> {
+        let backend = self
+            .state
+            .torrent_backend
+            .as_ref()
+            .ok_or(anyhow::anyhow!("torrent support is disabled"))?;
+
+        let files = backend.list_files(source).await?;
+        let Some(selector) = &self.state.torrent_file_selector else {
+            return Ok(files);
+        };
+
+        let indices = selector.select(&files).await?;
+        Ok(files
+            .into_iter()
+            .filter(|file| indices.contains(&file.index))
+            .collect())
+    }
+
+    /// Adds a single file of a torrent and returns its stream URL, skipping file selection.
+    ///
+    /// Like playing a torrent through its m3u, this replaces the currently active torrent
+    /// unless concurrent playback is enabled.
+    #[cfg(feature = "torrent")]
+    pub async fn register_torrent_file(
+        &self,
+        source: torrent::TorrentSource,
+        file_index: usize,
+    ) -> anyhow::Result<Url> {
+        let mut urls = self.add_torrent_files(source, vec![file_index]).await?;
+        Ok(urls.remove(0))
+    }
+
+    /// Like [`Self::register_torrent_file`] for several files, picked by their path in the
+    /// torrent or, when it is unambiguous, their name. Returns their stream URLs in the order
+    /// of `paths`.
+    ///
+    /// Fails, naming them, when any path doesn't match a file.
+    #[cfg(feature = "torrent")]
+    pub async fn register_torrent_by_paths(
+        &self,
+        source: torrent::TorrentSource,
+        paths: &[&str],
+    ) -> anyhow::Result<Vec<Url>> {
+        let backend = self
+            .state
+            .torrent_backend
+            .as_ref()
+            .ok_or(anyhow::anyhow!("torrent support is disabled"))?;
+
+        let files = backend.list_files(&source).await?;
+        let mut indices = Vec::with_capacity(paths.len());
+        let mut unmatched = Vec::new();
+        for path in paths {
+            match torrent::find_file(&files, path) {
+                Some(file) => indices.push(file.index),
+                None => unmatched.push(*path),
+            }
+        }
+        if !unmatched.is_empty() {
+            anyhow::bail!("no file in the torrent matches {}", unmatched.join(", "));
+        }
+
+        self.add_torrent_files(source, indices).await
+    }
+
+    /// Adds `file_indices` of a torrent and returns their stream URLs, in the same order.
+    #[cfg(feature = "torrent")]
+    async fn add_torrent_files(
+        &self,
+        source: torrent::TorrentSource,
+        file_indices: Vec<usize>,
+    ) -> anyhow::Result<Vec<Url>> {
+        let backend = self
+            .state
+            .torrent_backend
+            .as_ref()
+            .ok_or(anyhow::anyhow!("torrent support is disabled"))?;
+
+        if !self.state.torrent_concurrent_playback {
+            routes::clear_pending_torrents(&self.state).await;
+        }
+        routes::make_room_for_torrent(&self.state, backend.as_ref()).await;
+
+        let options = torrent::AddTorrentOptions {
+            file_indices: file_indices.clone(),
+        };
+        let added = backend.add_torrent(source, Some(options)).await?;
+
+        let urls = file_indices
+            .iter()
+            .map(|index| {
+                self.state
+                    .base_url
+                    .join(&format!("torrent/{}/stream/{}", added.id, index))
+            })
+            .collect::<Result<_, _>>()?;
+
+        self.state.active_torrents.write().await.push(added);
+        self.state.current_video.write().await.take();
+
+        Ok(urls)
+    }
+
+    /// Cancels a torrent, by its backend id or the resource id its m3u was listed under, and
+    /// deletes it from the backend. Returns whether it was active.
+    #[cfg(feature = "torrent")]
+    pub async fn stop_torrent(&self, torrent_id: &str) -> anyhow::Result<bool> {
+        let backend = self
+            .state
+            .torrent_backend
+            .as_ref()
+            .ok_or(anyhow::anyhow!("torrent support is disabled"))?;
+
+        let Some(torrent_id) = routes::started_torrent_id(&self.state, torrent_id).await else {
+            // Listed but never started, there is nothing to cancel in the backend.
+            routes::forget_pending_torrents(&self.state, &[torrent_id.to_owned()]).await;
+            return Ok(false);
+        };
+
+        let listed = self
+            .state
+            .pending_torrents
+            .read()
+            .await
+            .iter()
+            .filter(|(_, pending)| {
+                matches!(pending, routes::PendingTorrent::Started(id) if *id == torrent_id)
+            })
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+        routes::forget_pending_torrents(&self.state, &listed).await;
+
+        let active = {
+            let mut active = self.state.active_torrents.write().await;
+            let len = active.len();
+            active.retain(|torrent| torrent.id != torrent_id);
+            active.len() != len
+        };
+        if active {
+            backend.cancel_torrent(&torrent_id).await?;
+        }
+
+        Ok(active)
+    }
+
+    /// Reports whether the configured torrent backend is functional.
+    #[cfg(feature = "torrent")]
+    pub async fn torrent_health(&self) -> anyhow::Result<torrent::BackendHealth> {
+        let backend = self
+            .state
+            .torrent_backend
+            .as_ref()
+            .ok_or(anyhow::anyhow!("torrent support is disabled"))?;
+
+        backend.health().await
+    }
+
+    /// Pauses every torrent through [`torrent::TorrentBackend::shutdown`] and forgets the
+    /// active and listed ones, e.g. before the application exits. Downloaded data stays on disk,
+    /// and listed torrents stay in the resource backend so their stream URLs resolve again
+    /// after a restart.
+    ///
+    /// Torrents can't be streamed anymore afterwards.
+    #[cfg(feature = "torrent")]
+    pub async fn shutdown_torrents(&self) -> anyhow::Result<()> {
+        let Some(backend) = &self.state.torrent_backend else {
+            return Ok(());
+        };
+
+        self.state.pending_torrents.write().await.clear();
+        self.state.active_torrents.write().await.clear();
+        backend.shutdown().await
+    }
+
+    /// Reports the download progress of an added torrent, by its backend id.
+    ///
+    /// Fails when torrent support is disabled, the `/torrent/{torrent_id}/stats` route then
+    /// isn't served at all.
+    #[cfg(feature = "torrent")]
+    pub async fn torrent_stats(&self, torrent_id: &str) -> anyhow::Result<torrent::TorrentStats> {
+        let backend = self
+            .state
+            .torrent_backend
+            .as_ref()
+            .ok_or(anyhow::anyhow!("torrent support is disabled"))?;
+
+        backend.download_stats(torrent_id).await
+    }
+
+    /// Serves the proxy on `listener` until the process exits.
+    pub async fn run(&self, listener: tokio::net::TcpListener) -> anyhow::Result<()> {
+        self.run_with_shutdown(listener, std::future::pending())
+            .await
+    }
+
+    /// Serves the proxy on `listener` until `signal` completes, then drains open connections
+    /// and returns.
+    ///
+    /// Video and torrent streams last as long as the player keeps reading, which would hold the
+    /// drain up indefinitely. So once `signal` completes, proxied video fetches are cancelled
+    /// and torrents are shut down, ending their streams. Other requests in flight finish
+    /// normally. Processed images are dropped before returning. Registered resources are
+    /// kept, so a persistent or shared resource store still has them on the next start.
+    pub async fn run_with_shutdown(
+        &self,
+        listener: tokio::net::TcpListener,
+        signal: impl Future<Output = ()> + Send + 'static,
+    ) -> anyhow::Result<()> {
+        let proxy = Self {
+            state: self.state.clone(),
+        };
+        let drain = async move {
+            signal.await;
+            proxy.state.inflight.cancel_all();
+            #[cfg(feature = "torrent")]
+            if let Err(err) = proxy.shutdown_torrents().await {
+                tracing::warn!("Failed to shut down torrents: {err:#}");
+            }
+        };
+
+        axum::serve(listener, self.router())
+            .with_graceful_shutdown(drain)
+            .await?;
+
+        #[cfg(feature = "imaging")]
+        self.state.image_cache.clear().await;
+        Ok(())
+    }
+
     pub fn router(&self) -> Router {
         let base = Router::new()
             .route("/image/{resource_id}", get(handle_image_request))
-            .route("/video/{resource_id}", get(handle_video_request));
+            .route("/video/{resource_id}", get(handle_video_request))
+            .route("/health", get(routes::handle_health_request))
+            .route("/metrics", get(routes::handle_metrics_request))
+            .route("/ready", get(routes::handle_ready_request));
+
+        #[cfg(feature = "image-resize")]
+        let base = base.route(
+            "/image/{resource_id}/{size}",
+            get(routes::handle_image_variant_request),
+        );
 
         #[cfg(feature = "torrent")]
         let base = if self.state.torrent_backend.is_some() {
@@ -93,6 +639,14 @@ impl MediaProxy {
                 "/torrent/{torrent_id}/stream/{file_index}",
                 get(routes::handle_torrent_stream_request),
             )
+            .route(
+                "/torrent/{resource_id}/files",
+                get(routes::handle_torrent_files_request),
+            )
+            .route(
+                "/torrent/{torrent_id}/stats",
+                get(routes::handle_torrent_stats_request),
+            )
         } else {
             base
         };
@@ -100,3 +654,59 @@ impl MediaProxy {
         base.with_state(self.state.clone())
     }
 }
+
+/// Returns the base of the URLs handed out by the proxy, ending with a slash so that route
+/// paths can be joined onto it.
+fn base_url(addr: SocketAddr, public: Option<Url>) -> Url {
+    let mut url = public.unwrap_or_else(|| {
+        Url::parse(&format!("{}://{addr}/", Scheme::HTTP)).expect("socket address is a valid host")
+    });
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+    url
+}
+
+/// Extracts the key a resource is stored under from a proxy URL served by `route`, e.g.
+/// `/image/{id}` or `/image/{id}/{size}` for an image variant, relative to `base_url`.
+fn resource_key_from_url(url: &Url, base_url: &Url, route: &str) -> Option<String> {
+    let mut segments = url.path().strip_prefix(base_url.path())?.split('/');
+    if segments.next()? != route {
+        return None;
+    }
+
+    let id = segments.next()?.parse::<ResourceId>().ok()?;
+    match segments.next() {
+        Some(variant) if !variant.is_empty() => Some(format!("{id}/{variant}")),
+        _ => Some(id.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use super::resource_key_from_url;
+
+    #[test]
+    fn resource_keys_are_read_below_the_public_base_path() {
+        let base_url = Url::parse("https://media.example.com/proxy/").unwrap();
+        let key =
+            |url: &str, route| resource_key_from_url(&Url::parse(url).unwrap(), &base_url, route);
+
+        assert_eq!(
+            key("https://media.example.com/proxy/image/abc", "image").as_deref(),
+            Some("abc")
+        );
+        assert_eq!(
+            key("https://media.example.com/proxy/image/abc/64x64", "image").as_deref(),
+            Some("abc/64x64")
+        );
+        assert_eq!(
+            key("https://media.example.com/proxy/video/abc", "image"),
+            None
+        );
+        assert_eq!(key("https://media.example.com/image/abc", "image"), None);
+    }
+}
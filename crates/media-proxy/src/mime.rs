@@ -1,31 +1,204 @@
 use mime::Mime;
 use reqwest::Client;
-use std::str::FromStr;
+use std::{collections::HashMap, str::FromStr, sync::Arc, time::Duration};
 use tracing::{debug, warn};
 
-use crate::HttpRequest;
+use crate::{
+    HttpRequest,
+    utils::{Credential, HostCredentialsExt, IntoReqwestRequest},
+};
+
+/// Detects the MIME type of a resource before it is registered.
+#[async_trait::async_trait]
+pub trait MimeDetector: Send + Sync {
+    /// Returns `None` when the type couldn't be detected.
+    async fn detect(&self, client: &Client, request: &HttpRequest) -> anyhow::Result<Option<Mime>>;
+}
+
+pub const DEFAULT_DETECTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub const DEFAULT_SNIFF_BYTES: usize = 8 * 1024;
+
+/// How long a pending `HEAD` request may still take over an already sniffed type.
+const HEAD_GRACE: Duration = Duration::from_millis(50);
+
+/// Which stages [`DefaultMimeDetector`] runs. All of them are enabled by default.
+#[derive(Debug, Clone, Copy)]
+pub struct MimeDetectionConfig {
+    /// Guess from the extension in the URL path.
+    pub use_path: bool,
+    /// Read the `Content-Type` of a `HEAD` request. Some servers answer `HEAD` with `200` for
+    /// everything or `405`, making this stage only add latency.
+    pub use_head: bool,
+    /// Sniff the first bytes of the content. Runs concurrently with the `HEAD` request.
+    pub use_content_sniff: bool,
+    /// How many bytes content sniffing reads at most, stopping as soon as the type is
+    /// recognized. Defaults to [`DEFAULT_SNIFF_BYTES`].
+    pub sniff_bytes: usize,
+    /// Bounds the whole detection, so a dead host can't stall registration. Unbounded when
+    /// unset, defaults to [`DEFAULT_DETECTION_TIMEOUT`].
+    pub timeout: Option<Duration>,
+}
+
+impl Default for MimeDetectionConfig {
+    fn default() -> Self {
+        Self {
+            use_path: true,
+            use_head: true,
+            use_content_sniff: true,
+            sniff_bytes: DEFAULT_SNIFF_BYTES,
+            timeout: Some(DEFAULT_DETECTION_TIMEOUT),
+        }
+    }
+}
+
+impl MimeDetectionConfig {
+    /// Fails when every stage is disabled or content sniffing has no bytes to read.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(self.use_path || self.use_head || self.use_content_sniff) {
+            anyhow::bail!("at least one MIME detection stage must be enabled");
+        }
+        if self.use_content_sniff && self.sniff_bytes == 0 {
+            anyhow::bail!("content sniffing needs a byte budget");
+        }
+        Ok(())
+    }
+}
+
+/// Tries the URL path, then a `HEAD` request and the first bytes of the content at the same
+/// time, and finally the URL query, skipping the stages disabled in its
+/// [`MimeDetectionConfig`].
+#[derive(Debug, Default)]
+pub struct DefaultMimeDetector {
+    config: MimeDetectionConfig,
+    host_credentials: Arc<HashMap<String, Credential>>,
+}
+
+impl DefaultMimeDetector {
+    pub fn new(config: MimeDetectionConfig) -> anyhow::Result<Self> {
+        config.validate()?;
+        Ok(Self {
+            config,
+            host_credentials: Arc::default(),
+        })
+    }
+
+    /// Adds the credentials configured for a host to the network probes sent to it.
+    pub fn with_host_credentials(
+        mut self,
+        host_credentials: Arc<HashMap<String, Credential>>,
+    ) -> Self {
+        self.host_credentials = host_credentials;
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl MimeDetector for DefaultMimeDetector {
+    async fn detect(&self, client: &Client, request: &HttpRequest) -> anyhow::Result<Option<Mime>> {
+        Ok(mime_type(client, request, &self.config, &self.host_credentials).await?)
+    }
+}
 
 pub async fn mime_type(
     client: &Client,
     request: &HttpRequest,
+    config: &MimeDetectionConfig,
+    host_credentials: &HashMap<String, Credential>,
 ) -> Result<Option<Mime>, reqwest::Error> {
-    if let Some(mime) = detect_from_path(request) {
+    if config.use_path
+        && let Some(mime) = detect_from_path(request)
+    {
         debug!("MIME type detected from URL path: {}", mime);
         return Ok(Some(mime));
     }
 
-    if let Some(mime) = detect_from_head(client, request).await? {
-        debug!("MIME type detected from HEAD request: {}", mime);
+    // Network probes may fail on quirky servers, keep going with the remaining methods and
+    // only surface the error once nothing else worked.
+    let network = detect_from_network(client, request, config, host_credentials);
+    let (mime, last_err) = match config.timeout {
+        Some(timeout) => tokio::time::timeout(timeout, network)
+            .await
+            .unwrap_or_else(|_| {
+                debug!("MIME type detection timed out after {:?}", timeout);
+                (None, None)
+            }),
+        None => network.await,
+    };
+
+    if let Some(mime) = mime {
         return Ok(Some(mime));
     }
 
-    if let Some(mime) = detect_from_content(client, request).await? {
-        debug!("MIME type detected from content: {}", mime);
+    if let Some(mime) = detect_from_query(request) {
+        debug!("MIME type guessed from URL query: {}", mime);
         return Ok(Some(mime));
     }
 
-    warn!("Could not detect MIME type for URL: {}", request.uri());
-    Ok(None)
+    warn!(
+        "Could not detect MIME type for URL: {} (tried path, HEAD, content and query)",
+        request.uri()
+    );
+
+    match last_err {
+        Some(err) => Err(err),
+        None => Ok(None),
+    }
+}
+
+/// Races the `HEAD` and content sniffing stages, returning the first type found. Dropping the
+/// result drops the other probe along with its request.
+async fn detect_from_network(
+    client: &Client,
+    request: &HttpRequest,
+    config: &MimeDetectionConfig,
+    host_credentials: &HashMap<String, Credential>,
+) -> (Option<Mime>, Option<reqwest::Error>) {
+    let head = detect_from_head(client, request, host_credentials);
+    let content = detect_from_content(client, request, config.sniff_bytes);
+    tokio::pin!(head, content);
+
+    let mut head_done = !config.use_head;
+    let mut content_done = !config.use_content_sniff;
+    let mut last_err = None;
+
+    while !(head_done && content_done) {
+        // Biased so the declared `Content-Type` wins when both finish in the same poll.
+        let (stage, result) = tokio::select! {
+            biased;
+            result = &mut head, if !head_done => {
+                head_done = true;
+                ("HEAD request", result)
+            }
+            result = &mut content, if !content_done => {
+                content_done = true;
+                ("content", result)
+            }
+        };
+
+        match result {
+            Ok(Some(mime)) if stage == "content" && !head_done => {
+                // Give a `HEAD` answer arriving right behind the sniffed one precedence.
+                if let Ok(Ok(Some(declared))) = tokio::time::timeout(HEAD_GRACE, &mut head).await {
+                    debug!("MIME type detected from HEAD request: {}", declared);
+                    return (Some(declared), None);
+                }
+                debug!("MIME type detected from content: {}", mime);
+                return (Some(mime), None);
+            }
+            Ok(Some(mime)) => {
+                debug!("MIME type detected from {}: {}", stage, mime);
+                return (Some(mime), None);
+            }
+            Ok(None) => {}
+            Err(err) => {
+                debug!("{} failed: {}", stage, err);
+                last_err = Some(err);
+            }
+        }
+    }
+
+    (None, last_err)
 }
 
 fn detect_from_path(request: &HttpRequest) -> Option<Mime> {
@@ -40,11 +213,36 @@ fn detect_from_path(request: &HttpRequest) -> Option<Mime> {
     Some(mime)
 }
 
+/// Looks for a file extension in the query string, e.g. `?file=episode.mp4`.
+fn detect_from_query(request: &HttpRequest) -> Option<Mime> {
+    let query = request.uri().query()?;
+
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('=').map(|(_, value)| value))
+        .filter_map(|value| {
+            let (_, extension) = value.rsplit_once('.')?;
+            mime_guess::from_ext(extension).first()
+        })
+        .next()
+}
+
+/// Sends a `HEAD` request with the stored request's headers, so servers requiring a referer,
+/// a cookie or credentials answer it like the actual fetch.
 async fn detect_from_head(
     client: &Client,
     request: &HttpRequest,
+    host_credentials: &HashMap<String, Credential>,
 ) -> Result<Option<Mime>, reqwest::Error> {
-    let res = client.head(request.uri().to_string()).send().await?;
+    let mut head = http::Request::head(request.uri())
+        .body(None)
+        .expect("a request built from a valid URI");
+    *head.headers_mut() = request.headers().clone();
+    head.apply_host_credentials(host_credentials);
+
+    let res = client
+        .execute(head.into_reqwest_request(client.clone())?)
+        .await?;
 
     if !res.status().is_success() {
         debug!("HEAD request failed with status: {}", res.status());
@@ -68,10 +266,17 @@ async fn detect_from_head(
 async fn detect_from_content(
     client: &Client,
     request: &HttpRequest,
+    budget: usize,
 ) -> Result<Option<Mime>, reqwest::Error> {
+    let mut headers = request.headers().clone();
+    // Only the first bytes are sniffed, don't have the server start sending a whole video.
+    if let Ok(range) = http::HeaderValue::from_str(&format!("bytes=0-{}", budget - 1)) {
+        headers.entry(http::header::RANGE).or_insert(range);
+    }
+
     let mut req = client
         .request(request.method().clone(), request.uri().to_string())
-        .headers(request.headers().clone());
+        .headers(headers);
 
     if let Some(body) = request.body() {
         req = req.body(body.clone());
@@ -84,11 +289,20 @@ async fn detect_from_content(
         return Ok(None);
     }
 
-    let Some(chunk) = res.chunk().await? else {
-        return Ok(None);
-    };
+    // Some containers can't be told apart from their first chunk alone, e.g. an MP4 whose
+    // server flushes a few bytes at a time.
+    let mut buf = Vec::new();
+    while buf.len() < budget {
+        let Some(chunk) = res.chunk().await? else {
+            break;
+        };
+        let take = chunk.len().min(budget - buf.len());
+        buf.extend_from_slice(&chunk[..take]);
 
-    let mime = infer::get(&chunk).and_then(|kind| Mime::from_str(kind.mime_type()).ok());
+        if let Some(kind) = infer::get(&buf) {
+            return Ok(Mime::from_str(kind.mime_type()).ok());
+        }
+    }
 
-    Ok(mime)
+    Ok(None)
 }
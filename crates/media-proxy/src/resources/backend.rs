@@ -0,0 +1,286 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use mime::Mime;
+use tokio::{sync::RwLock, time};
+
+use super::Resource;
+
+/// A registered resource along with the MIME type detected when it was registered.
+#[derive(Debug, Clone)]
+pub struct StoredResource {
+    pub resource: Resource,
+    pub mime: Option<Mime>,
+}
+
+/// Storage behind a [`super::ResourceStore`].
+///
+/// Sharing a backend between several proxies lets a resource registered on one of them be
+/// served by another.
+#[async_trait::async_trait]
+pub trait ResourceBackend: Send + Sync {
+    async fn get(&self, id: &str) -> Result<Option<StoredResource>>;
+
+    /// Stores `resource` under `id`, replacing any previous one. It is dropped once `ttl`
+    /// elapses.
+    async fn insert(
+        &self,
+        id: String,
+        resource: StoredResource,
+        ttl: Option<Duration>,
+    ) -> Result<()>;
+
+    async fn remove(&self, id: &str) -> Result<Option<StoredResource>>;
+
+    /// Drops every stored resource.
+    async fn clear(&self) -> Result<()>;
+
+    /// Number of resources currently stored, used to enforce the store capacity.
+    ///
+    /// This is called on every registration when the store has a capacity, so it shouldn't
+    /// walk every stored resource.
+    async fn count(&self) -> Result<usize>;
+
+    /// Number of resources dropped because they expired, if the backend keeps track of it.
+    fn evictions(&self) -> u64 {
+        0
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    resource: StoredResource,
+    expires_at: Option<Instant>,
+}
+
+impl Entry {
+    fn is_expired(&self) -> bool {
+        self.expires_at
+            .map(|e| Instant::now() >= e)
+            .unwrap_or(false)
+    }
+}
+
+type EvictionHandler = dyn Fn(String, StoredResource) + Send + Sync;
+
+/// Counts expired entries and hands them to the eviction handler, if any.
+#[derive(Clone, Default)]
+struct Evictions {
+    count: Arc<AtomicU64>,
+    handler: Option<Arc<EvictionHandler>>,
+}
+
+impl Evictions {
+    /// Must be called without holding the entries lock, the handler may use the backend.
+    fn notify(&self, evicted: Vec<(String, StoredResource)>) {
+        self.count
+            .fetch_add(evicted.len() as u64, Ordering::Relaxed);
+        if let Some(handler) = &self.handler {
+            for (id, resource) in evicted {
+                handler(id, resource);
+            }
+        }
+    }
+}
+
+/// Keeps resources in the process memory. This is the default backend.
+#[derive(Default)]
+pub struct MemoryBackend {
+    entries: Arc<RwLock<HashMap<String, Entry>>>,
+    cleanup_started: AtomicBool,
+    evictions: Evictions,
+}
+
+impl MemoryBackend {
+    /// Calls `handler` with every resource dropped because it expired, e.g. to cancel the
+    /// torrent behind it. Resources taken out through [`ResourceBackend::remove`] are not
+    /// reported.
+    ///
+    /// The handler runs once the entry is already gone and without holding any lock, so it
+    /// may use the backend. A resource inserted under the same id in the meantime is kept,
+    /// and can be visible before the handler for the expired one has run.
+    pub fn with_eviction_handler(
+        mut self,
+        handler: impl Fn(String, StoredResource) + Send + Sync + 'static,
+    ) -> Self {
+        self.evictions.handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// Periodically drops expired entries. Started with the first entry that has a TTL.
+    fn spawn_cleanup_task(&self) {
+        if self.cleanup_started.swap(true, Ordering::Relaxed) {
+            return;
+        }
+
+        let entries = Arc::clone(&self.entries);
+        let evictions = self.evictions.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                let expired = entries
+                    .write()
+                    .await
+                    .extract_if(|_, entry| entry.is_expired())
+                    .map(|(id, entry)| (id, entry.resource))
+                    .collect::<Vec<_>>();
+                evictions.notify(expired);
+            }
+        });
+    }
+}
+
+#[async_trait::async_trait]
+impl ResourceBackend for MemoryBackend {
+    async fn get(&self, id: &str) -> Result<Option<StoredResource>> {
+        let entries = self.entries.read().await;
+        Ok(entries
+            .get(id)
+            .filter(|entry| !entry.is_expired())
+            .map(|entry| entry.resource.clone()))
+    }
+
+    async fn insert(
+        &self,
+        id: String,
+        resource: StoredResource,
+        ttl: Option<Duration>,
+    ) -> Result<()> {
+        if ttl.is_some() {
+            self.spawn_cleanup_task();
+        }
+
+        let entry = Entry {
+            resource,
+            expires_at: ttl.map(|d| Instant::now() + d),
+        };
+        self.entries.write().await.insert(id, entry);
+        Ok(())
+    }
+
+    async fn remove(&self, id: &str) -> Result<Option<StoredResource>> {
+        let entry = self.entries.write().await.remove(id);
+        Ok(match entry {
+            Some(entry) if entry.is_expired() => {
+                self.evictions.notify(vec![(id.to_owned(), entry.resource)]);
+                None
+            }
+            entry => entry.map(|entry| entry.resource),
+        })
+    }
+
+    async fn clear(&self) -> Result<()> {
+        self.entries.write().await.clear();
+        Ok(())
+    }
+
+    async fn count(&self) -> Result<usize> {
+        Ok(self.entries.read().await.len())
+    }
+
+    fn evictions(&self) -> u64 {
+        self.evictions.count.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use super::{MemoryBackend, ResourceBackend, StoredResource};
+    use crate::resources::Resource;
+
+    fn resource(uri: &str) -> StoredResource {
+        let req = http::Request::get(uri).body(None).unwrap();
+        StoredResource {
+            resource: Resource::Http(Box::new(req)),
+            mime: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn entries_expire_after_their_own_ttl() {
+        let backend = MemoryBackend::default();
+        let short = Duration::from_millis(20);
+        let long = Duration::from_secs(60);
+
+        backend
+            .insert("short".into(), resource("http://a/1"), Some(short))
+            .await
+            .unwrap();
+        backend
+            .insert("long".into(), resource("http://a/2"), Some(long))
+            .await
+            .unwrap();
+        backend
+            .insert("forever".into(), resource("http://a/3"), None)
+            .await
+            .unwrap();
+
+        assert!(backend.get("short").await.unwrap().is_some());
+
+        tokio::time::sleep(short * 2).await;
+
+        assert!(backend.get("short").await.unwrap().is_none());
+        assert!(backend.get("long").await.unwrap().is_some());
+        assert!(backend.get("forever").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn expired_entries_are_not_removed_as_live() {
+        let backend = MemoryBackend::default();
+        let short = Duration::from_millis(20);
+
+        backend
+            .insert("short".into(), resource("http://a/1"), Some(short))
+            .await
+            .unwrap();
+        backend
+            .insert("long".into(), resource("http://a/2"), Some(short * 100))
+            .await
+            .unwrap();
+
+        tokio::time::sleep(short * 2).await;
+
+        assert!(backend.remove("short").await.unwrap().is_none());
+        assert!(backend.remove("long").await.unwrap().is_some());
+        assert_eq!(backend.evictions(), 1);
+    }
+
+    #[tokio::test]
+    async fn eviction_handler_only_sees_expired_entries() {
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let backend = MemoryBackend::default().with_eviction_handler({
+            let evicted = evicted.clone();
+            move |id, _| evicted.lock().unwrap().push(id)
+        });
+        let short = Duration::from_millis(20);
+
+        backend
+            .insert("short".into(), resource("http://a/1"), Some(short))
+            .await
+            .unwrap();
+        backend
+            .insert("live".into(), resource("http://a/2"), None)
+            .await
+            .unwrap();
+
+        tokio::time::sleep(short * 2).await;
+
+        backend.remove("short").await.unwrap();
+        backend.remove("live").await.unwrap();
+
+        assert_eq!(*evicted.lock().unwrap(), ["short"]);
+    }
+}
@@ -0,0 +1,130 @@
+//! Serialization of stored resources for backends keeping them outside the process.
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use super::{Resource, StoredResource};
+use crate::HttpRequest;
+#[cfg(feature = "torrent")]
+use crate::torrent::{AddTorrentOptions, TorrentSource};
+
+#[derive(Serialize, Deserialize)]
+struct EncodedRequest {
+    method: String,
+    uri: String,
+    headers: Vec<(String, Vec<u8>)>,
+    body: Option<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize)]
+enum EncodedResource {
+    Http(EncodedRequest),
+    #[cfg(feature = "torrent")]
+    TorrentHttp(EncodedRequest),
+    #[cfg(feature = "torrent")]
+    TorrentMagnetUri(String),
+    #[cfg(feature = "torrent")]
+    PendingTorrent {
+        source: Box<EncodedResource>,
+        file_indices: Option<Vec<usize>>,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct EncodedEntry {
+    resource: EncodedResource,
+    mime: Option<String>,
+}
+
+impl From<&HttpRequest> for EncodedRequest {
+    fn from(req: &HttpRequest) -> Self {
+        Self {
+            method: req.method().to_string(),
+            uri: req.uri().to_string(),
+            headers: req
+                .headers()
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.as_bytes().to_vec()))
+                .collect(),
+            body: req.body().as_ref().map(|body| body.to_vec()),
+        }
+    }
+}
+
+impl TryFrom<EncodedRequest> for HttpRequest {
+    type Error = anyhow::Error;
+
+    fn try_from(req: EncodedRequest) -> Result<Self> {
+        let mut builder = http::Request::builder()
+            .method(req.method.as_str())
+            .uri(req.uri);
+        for (name, value) in req.headers {
+            builder = builder.header(name, value);
+        }
+        Ok(builder.body(req.body.map(Bytes::from))?)
+    }
+}
+
+#[cfg(feature = "torrent")]
+impl From<&TorrentSource> for EncodedResource {
+    fn from(source: &TorrentSource) -> Self {
+        match source {
+            TorrentSource::Http(req) => Self::TorrentHttp(req.as_ref().into()),
+            TorrentSource::MagnetUri(uri) => Self::TorrentMagnetUri(uri.clone()),
+        }
+    }
+}
+
+pub(super) fn encode(stored: &StoredResource) -> Result<Vec<u8>> {
+    let resource = match &stored.resource {
+        Resource::Http(req) => EncodedResource::Http(req.as_ref().into()),
+        #[cfg(feature = "torrent")]
+        Resource::Torrent(source) => source.into(),
+        #[cfg(feature = "torrent")]
+        Resource::PendingTorrent { source, options } => EncodedResource::PendingTorrent {
+            source: Box::new(source.into()),
+            file_indices: options.as_ref().map(|options| options.file_indices.clone()),
+        },
+    };
+
+    let entry = EncodedEntry {
+        resource,
+        mime: stored.mime.as_ref().map(ToString::to_string),
+    };
+    Ok(serde_json::to_vec(&entry)?)
+}
+
+pub(super) fn decode(bytes: &[u8]) -> Result<StoredResource> {
+    let entry: EncodedEntry = serde_json::from_slice(bytes).context("malformed stored resource")?;
+
+    Ok(StoredResource {
+        resource: decode_resource(entry.resource)?,
+        mime: entry.mime.and_then(|mime| mime.parse().ok()),
+    })
+}
+
+fn decode_resource(resource: EncodedResource) -> Result<Resource> {
+    Ok(match resource {
+        EncodedResource::Http(req) => Resource::Http(Box::new(req.try_into()?)),
+        #[cfg(feature = "torrent")]
+        EncodedResource::TorrentHttp(req) => {
+            Resource::Torrent(TorrentSource::Http(Box::new(req.try_into()?)))
+        }
+        #[cfg(feature = "torrent")]
+        EncodedResource::TorrentMagnetUri(uri) => Resource::Torrent(TorrentSource::MagnetUri(uri)),
+        #[cfg(feature = "torrent")]
+        EncodedResource::PendingTorrent {
+            source,
+            file_indices,
+        } => {
+            let Resource::Torrent(source) = decode_resource(*source)? else {
+                anyhow::bail!("malformed pending torrent");
+            };
+            Resource::PendingTorrent {
+                source,
+                options: file_indices.map(|file_indices| AddTorrentOptions { file_indices }),
+            }
+        }
+    })
+}
@@ -0,0 +1,284 @@
+use std::{
+    path::PathBuf,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use nero_file_store::FileStore;
+use tokio::{task::spawn_blocking, time};
+
+use super::{
+    ResourceBackend, StoredResource,
+    encoding::{decode, encode},
+};
+
+const LIST_PAGE_SIZE: usize = 1000;
+
+/// Keeps resources on disk, so that URLs handed out before a restart, such as a torrent's
+/// playlist, still resolve after it.
+pub struct FileBackend {
+    store: Arc<FileStore>,
+    /// Live entries, counted when opening and kept up to date on writes. Entries don't tell
+    /// when they expire, so they are recounted from disk periodically once one has a TTL.
+    ///
+    /// Held across the existence check and the write, so concurrent writes to the same id
+    /// count it once.
+    count: Arc<Mutex<usize>>,
+    recount_started: AtomicBool,
+}
+
+impl FileBackend {
+    /// Opens the store in `root`, creating the directory if needed.
+    pub async fn open(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        let store = Arc::new(spawn_blocking(move || FileStore::new(root, None)).await??);
+        let count = blocking(&store, count_keys).await?;
+        Ok(Self {
+            store,
+            count: Arc::new(Mutex::new(count)),
+            recount_started: AtomicBool::new(false),
+        })
+    }
+
+    async fn blocking<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&FileStore) -> Result<T, nero_file_store::Error> + Send + 'static,
+    {
+        blocking(&self.store, f).await
+    }
+
+    /// Periodically recounts the live entries, until the backend is dropped. Started with the
+    /// first entry that has a TTL.
+    fn spawn_recount_task(&self) {
+        if self.recount_started.swap(true, Ordering::Relaxed) {
+            return;
+        }
+
+        let store = Arc::downgrade(&self.store);
+        let count = Arc::downgrade(&self.count);
+        tokio::spawn(async move {
+            let mut interval = time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                let (Some(store), Some(count)) = (store.upgrade(), count.upgrade()) else {
+                    return;
+                };
+                let recounted = blocking(&store, move |store| {
+                    let mut count = count.lock().unwrap();
+                    *count = count_keys(store)?;
+                    Ok(())
+                })
+                .await;
+                if let Err(err) = recounted {
+                    tracing::warn!("Failed to count stored resources: {err:#}");
+                }
+            }
+        });
+    }
+}
+
+async fn blocking<T, F>(store: &Arc<FileStore>, f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&FileStore) -> Result<T, nero_file_store::Error> + Send + 'static,
+{
+    let store = store.clone();
+    Ok(spawn_blocking(move || f(&store)).await??)
+}
+
+fn count_keys(store: &FileStore) -> Result<usize, nero_file_store::Error> {
+    let mut count = 0;
+    let mut cursor = None;
+    loop {
+        let (page, next) = store.list_keys(cursor.as_deref(), LIST_PAGE_SIZE)?;
+        count += page.len();
+        match next {
+            Some(next) => cursor = Some(next),
+            None => return Ok(count),
+        }
+    }
+}
+
+async fn keys(store: &Arc<FileStore>) -> Result<Vec<String>> {
+    let mut keys = Vec::new();
+    let mut cursor = None;
+    loop {
+        let (page, next) = blocking(store, move |store| {
+            store.list_keys(cursor.as_deref(), LIST_PAGE_SIZE)
+        })
+        .await?;
+        keys.extend(page);
+        match next {
+            Some(next) => cursor = Some(next),
+            None => return Ok(keys),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl ResourceBackend for FileBackend {
+    async fn get(&self, id: &str) -> Result<Option<StoredResource>> {
+        let id = id.to_owned();
+        let value = self.blocking(move |store| store.get(&id)).await?;
+        value.map(|v| decode(&v)).transpose()
+    }
+
+    async fn insert(
+        &self,
+        id: String,
+        resource: StoredResource,
+        ttl: Option<Duration>,
+    ) -> Result<()> {
+        if ttl.is_some() {
+            self.spawn_recount_task();
+        }
+
+        let value = encode(&resource)?;
+        let ttl_ms = ttl.map(|ttl| u32::try_from(ttl.as_millis()).unwrap_or(u32::MAX).max(1));
+        let count = Arc::clone(&self.count);
+        self.blocking(move |store| {
+            let mut count = count.lock().unwrap();
+            let existed = store.exists(&id)?;
+            store.set(&id, value, ttl_ms)?;
+            if !existed {
+                *count += 1;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn remove(&self, id: &str) -> Result<Option<StoredResource>> {
+        let id = id.to_owned();
+        let count = Arc::clone(&self.count);
+        let value = self
+            .blocking(move |store| {
+                let mut count = count.lock().unwrap();
+                let value = store.get(&id)?;
+                store.delete(&id)?;
+                if value.is_some() {
+                    *count = count.saturating_sub(1);
+                }
+                Ok(value)
+            })
+            .await?;
+        value.map(|v| decode(&v)).transpose()
+    }
+
+    async fn clear(&self) -> Result<()> {
+        for key in keys(&self.store).await? {
+            self.blocking(move |store| store.delete(&key)).await?;
+        }
+        let count = Arc::clone(&self.count);
+        self.blocking(move |store| {
+            *count.lock().unwrap() = count_keys(store)?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn count(&self) -> Result<usize> {
+        Ok(*self.count.lock().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::{FileBackend, ResourceBackend, StoredResource};
+    use crate::resources::Resource;
+
+    #[tokio::test]
+    async fn resources_survive_reopening() {
+        let dir = tempdir().unwrap();
+        let req = http::Request::get("http://a/video.mp4")
+            .header("referer", "http://a/")
+            .body(None)
+            .unwrap();
+
+        let backend = FileBackend::open(dir.path()).await.unwrap();
+        let stored = StoredResource {
+            resource: Resource::Http(Box::new(req)),
+            mime: Some(mime::APPLICATION_OCTET_STREAM),
+        };
+        backend.insert("id".into(), stored, None).await.unwrap();
+        drop(backend);
+
+        let backend = FileBackend::open(dir.path()).await.unwrap();
+        assert_eq!(backend.count().await.unwrap(), 1);
+
+        let stored = backend.remove("id").await.unwrap().unwrap();
+        assert_eq!(backend.count().await.unwrap(), 0);
+        #[allow(irrefutable_let_patterns)]
+        let Resource::Http(req) = stored.resource else {
+            panic!("expected an HTTP resource");
+        };
+        assert_eq!(req.uri(), "http://a/video.mp4");
+        assert_eq!(req.headers()["referer"], "http://a/");
+        assert!(backend.get("id").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn concurrent_inserts_of_an_id_count_once() {
+        let dir = tempdir().unwrap();
+        let backend = std::sync::Arc::new(FileBackend::open(dir.path()).await.unwrap());
+
+        let inserts = (0..8).map(|_| {
+            let backend = backend.clone();
+            tokio::spawn(async move {
+                let req = http::Request::get("http://a/image.png").body(None).unwrap();
+                let stored = StoredResource {
+                    resource: Resource::Http(Box::new(req)),
+                    mime: None,
+                };
+                backend.insert("id".into(), stored, None).await.unwrap();
+            })
+        });
+        for insert in inserts.collect::<Vec<_>>() {
+            insert.await.unwrap();
+        }
+
+        assert_eq!(backend.count().await.unwrap(), 1);
+    }
+
+    #[cfg(feature = "torrent")]
+    #[tokio::test]
+    async fn pending_torrents_keep_their_file_selection() {
+        use crate::torrent::{AddTorrentOptions, TorrentSource};
+
+        let dir = tempdir().unwrap();
+        let backend = FileBackend::open(dir.path()).await.unwrap();
+        let stored = StoredResource {
+            resource: Resource::PendingTorrent {
+                source: TorrentSource::MagnetUri("magnet:?xt=urn:btih:abc".into()),
+                options: Some(AddTorrentOptions {
+                    file_indices: vec![1, 3],
+                }),
+            },
+            mime: None,
+        };
+        backend
+            .insert("id/pending".into(), stored, None)
+            .await
+            .unwrap();
+        drop(backend);
+
+        let backend = FileBackend::open(dir.path()).await.unwrap();
+        let stored = backend.get("id/pending").await.unwrap().unwrap();
+        let Resource::PendingTorrent {
+            source: TorrentSource::MagnetUri(uri),
+            options: Some(options),
+        } = stored.resource
+        else {
+            panic!("expected a pending magnet torrent");
+        };
+        assert_eq!(uri, "magnet:?xt=urn:btih:abc");
+        assert_eq!(options.file_indices, [1, 3]);
+    }
+}
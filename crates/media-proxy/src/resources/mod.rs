@@ -0,0 +1,580 @@
+mod backend;
+#[cfg(any(feature = "file-backend", feature = "redis"))]
+mod encoding;
+#[cfg(feature = "file-backend")]
+mod file;
+#[cfg(feature = "redis")]
+mod redis;
+
+use std::{collections::HashMap, fmt, ops::Deref, str::FromStr, sync::Arc, time::Duration};
+
+use anyhow::bail;
+use mime::Mime;
+use tracing::instrument;
+use url::Url;
+
+pub use backend::{MemoryBackend, ResourceBackend, StoredResource};
+#[cfg(feature = "file-backend")]
+pub use file::FileBackend;
+#[cfg(feature = "redis")]
+pub use redis::RedisBackend;
+
+#[cfg(feature = "image-resize")]
+use crate::imaging::{ImageSize, ImageVariants};
+#[cfg(feature = "torrent")]
+use crate::torrent::{AddTorrentOptions, TorrentSource};
+use crate::{
+    HttpRequest,
+    mime::{DefaultMimeDetector, MimeDetector},
+    stats::{CacheCounters, CacheStats},
+    utils::{Credential, HostCredentialsExt},
+};
+
+/// How long [`ResourceStore::probe_live_stream`] may take, both playlists included.
+pub const LIVE_PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Size past which [`ResourceStore::probe_live_stream`] stops reading a playlist. Live
+/// playlists only list a sliding window of segments, so they stay well under it.
+pub const MAX_PROBED_PLAYLIST_BYTES: usize = 256 * 1024;
+
+/// Returned when registering a resource whose MIME type couldn't be detected.
+#[derive(Debug, thiserror::Error)]
+#[error("Could not detect mime type")]
+pub struct UndetectedMediaType;
+
+/// Returned when registering a resource while the store already holds its configured capacity.
+#[derive(Debug, thiserror::Error)]
+#[error("resource store is at capacity")]
+pub struct StoreAtCapacity;
+
+/// Returned when registering a resource whose MIME type is neither an allowed image nor video
+/// type.
+#[derive(Debug, thiserror::Error)]
+pub struct UnsupportedMediaType(pub Mime);
+
+impl fmt::Display for UnsupportedMediaType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // CDNs tend to answer with a login page rather than an error when credentials are
+        // missing, which would otherwise show up as a puzzling unsupported type.
+        if self.0.essence_str() == mime::TEXT_HTML.essence_str() {
+            return write!(
+                f,
+                "Unsupported media type: upstream returned HTML, likely an auth or redirect \
+                 page. Check the credentials and headers of the request"
+            );
+        }
+        write!(f, "Unsupported media type: {}", self.0)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Resource {
+    Http(Box<HttpRequest>),
+    #[cfg(feature = "torrent")]
+    Torrent(TorrentSource),
+    /// A torrent whose playlist was served, added to the torrent backend on the first request
+    /// for one of its streams. Only stored by the proxy itself, it can't be registered.
+    #[cfg(feature = "torrent")]
+    PendingTorrent {
+        source: TorrentSource,
+        options: Option<AddTorrentOptions>,
+    },
+}
+
+/// Identifier of a registered resource, as it appears in the proxy URLs.
+///
+/// Ids are restricted to ASCII alphanumerics, `-`, `_` and `.` so they can be used as a path
+/// segment as is.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ResourceId(String);
+
+impl FromStr for ResourceId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            bail!("resource id is empty");
+        }
+        if let Some(c) = s
+            .chars()
+            .find(|c| !c.is_ascii_alphanumeric() && !matches!(c, '-' | '_' | '.'))
+        {
+            bail!("invalid character {c:?} in resource id");
+        }
+        Ok(Self(s.to_owned()))
+    }
+}
+
+impl fmt::Display for ResourceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Deref for ResourceId {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+pub struct ResourceStoreConfig {
+    pub ttl: Option<Duration>,
+    pub capacity: Option<usize>,
+    /// MIME types served through the image route. A `*` subtype matches the whole type.
+    pub image_types: Vec<Mime>,
+    /// MIME types served through the video route. A `*` subtype matches the whole type.
+    pub video_types: Vec<Mime>,
+    /// Where resources are kept, a [`MemoryBackend`] when unset.
+    pub backend: Option<Arc<dyn ResourceBackend>>,
+    /// Detects the type of registered requests, a [`DefaultMimeDetector`] when unset.
+    ///
+    /// [`MediaProxyConfig::mime_detection`](crate::MediaProxyConfig::mime_detection) is
+    /// ignored when this is set.
+    pub mime_detector: Option<Arc<dyn MimeDetector>>,
+}
+
+impl Default for ResourceStoreConfig {
+    fn default() -> Self {
+        Self {
+            ttl: None,
+            capacity: None,
+            image_types: vec![mime::IMAGE_STAR],
+            video_types: vec!["video/*".parse().unwrap()],
+            backend: None,
+            mime_detector: None,
+        }
+    }
+}
+
+fn is_allowed(allowed: &[Mime], mime: &Mime) -> bool {
+    allowed.iter().any(|pattern| {
+        pattern.type_() == mime.type_()
+            && (pattern.subtype() == mime::STAR || pattern.subtype() == mime.subtype())
+    })
+}
+
+/// Key a pending torrent is stored under, which no resource id can collide with as ids can't
+/// contain `/`.
+#[cfg(feature = "torrent")]
+fn pending_torrent_key(id: &str) -> String {
+    format!("{id}/pending")
+}
+
+pub struct ResourceStore {
+    base_url: Url,
+    http_client: reqwest::Client,
+    host_credentials: Arc<HashMap<String, Credential>>,
+    backend: Arc<dyn ResourceBackend>,
+    mime_detector: Arc<dyn MimeDetector>,
+    counters: CacheCounters,
+    ttl: Option<Duration>,
+    capacity: Option<usize>,
+    image_types: Vec<Mime>,
+    video_types: Vec<Mime>,
+}
+
+impl ResourceStore {
+    pub(crate) fn new(
+        base_url: Url,
+        http_client: reqwest::Client,
+        host_credentials: Arc<HashMap<String, Credential>>,
+        config: ResourceStoreConfig,
+    ) -> Self {
+        let backend = config
+            .backend
+            .unwrap_or_else(|| Arc::new(MemoryBackend::default()));
+
+        Self {
+            base_url,
+            http_client,
+            host_credentials,
+            backend,
+            mime_detector: config
+                .mime_detector
+                .unwrap_or_else(|| Arc::new(DefaultMimeDetector::default())),
+            counters: CacheCounters::default(),
+            ttl: config.ttl,
+            capacity: config.capacity,
+            image_types: config.image_types,
+            video_types: config.video_types,
+        }
+    }
+
+    async fn save(
+        &self,
+        id: String,
+        resource: Resource,
+        mime: Option<Mime>,
+        ttl: Option<Duration>,
+    ) -> anyhow::Result<()> {
+        if let Some(max) = self.capacity
+            && self.backend.count().await? >= max
+            && self.backend.get(&id).await?.is_none()
+        {
+            return Err(StoreAtCapacity.into());
+        }
+
+        let resource = StoredResource { resource, mime };
+        self.backend.insert(id, resource, ttl).await
+    }
+
+    /// Base of the URLs handed out for registered resources.
+    pub(crate) fn base_url(&self) -> &Url {
+        &self.base_url
+    }
+
+    /// Returns a copy of `req` carrying the configured host credentials, to probe it.
+    fn with_credentials(&self, req: &HttpRequest) -> HttpRequest {
+        let mut req = req.clone();
+        req.apply_host_credentials(&self.host_credentials);
+        req
+    }
+
+    async fn insert_http(
+        &self,
+        id: String,
+        req: Box<HttpRequest>,
+        mime_hint: Option<Mime>,
+        ttl: Option<Duration>,
+    ) -> anyhow::Result<Url> {
+        if req.headers().is_empty() && req.body().is_none() {
+            return Ok(Url::parse(&req.uri().to_string())?);
+        }
+
+        let mime_type = match mime_hint {
+            Some(mime) => mime,
+            None => self
+                .mime_detector
+                .detect(&self.http_client, &self.with_credentials(&req))
+                .await?
+                .ok_or(UndetectedMediaType)?,
+        };
+
+        #[cfg(feature = "torrent")]
+        if mime_type.essence_str() == "application/x-bittorrent" {
+            let resource = Resource::Torrent(TorrentSource::Http(req));
+            let url = self.base_url.join(&format!("torrent/{id}"))?;
+            self.save(id, resource, Some(mime_type), ttl).await?;
+            return Ok(url);
+        }
+
+        let path = if is_allowed(&self.image_types, &mime_type) {
+            "image"
+        } else if is_allowed(&self.video_types, &mime_type) {
+            "video"
+        } else {
+            return Err(UnsupportedMediaType(mime_type).into());
+        };
+
+        let url = self.base_url.join(&format!("{path}/{id}"))?;
+        self.save(id, Resource::Http(req), Some(mime_type), ttl)
+            .await?;
+
+        Ok(url)
+    }
+
+    /// Registers a resource and returns the URL it is served at.
+    ///
+    /// Failures to register the media itself are reported as [`UnsupportedMediaType`],
+    /// [`UndetectedMediaType`] or [`StoreAtCapacity`], to be told apart with
+    /// [`anyhow::Error::is`].
+    pub async fn insert(&self, id: String, resource: Resource) -> anyhow::Result<Url> {
+        self.insert_expiring(id, resource, self.ttl).await
+    }
+
+    /// Like [`Self::insert`] for an HTTP request, trusting `mime_hint` instead of detecting the
+    /// MIME type when it parses as one.
+    #[instrument(skip_all, fields(resource_id = %id))]
+    pub async fn insert_with_mime_hint(
+        &self,
+        id: String,
+        req: Box<HttpRequest>,
+        mime_hint: &str,
+    ) -> anyhow::Result<Url> {
+        id.parse::<ResourceId>()?;
+
+        let mime_hint = mime_hint
+            .parse::<Mime>()
+            .inspect_err(|_| tracing::debug!("Ignoring invalid MIME type hint: {mime_hint}"))
+            .ok();
+        self.insert_http(id, req, mime_hint, self.ttl).await
+    }
+
+    /// Like [`Self::insert`], but the resource expires after `ttl` instead of the store's
+    /// default, e.g. a short one for a live stream.
+    pub async fn insert_with_ttl(
+        &self,
+        id: String,
+        resource: Resource,
+        ttl: Duration,
+    ) -> anyhow::Result<Url> {
+        self.insert_expiring(id, resource, Some(ttl)).await
+    }
+
+    #[instrument(skip_all, fields(resource_id = %id))]
+    async fn insert_expiring(
+        &self,
+        id: String,
+        resource: Resource,
+        ttl: Option<Duration>,
+    ) -> anyhow::Result<Url> {
+        id.parse::<ResourceId>()?;
+
+        match resource {
+            Resource::Http(req) => self.insert_http(id, req, None, ttl).await,
+            #[cfg(feature = "torrent")]
+            Resource::Torrent(src) => {
+                let url = self.base_url.join(&format!("torrent/{id}"))?;
+                self.save(id, Resource::Torrent(src), None, ttl).await?;
+                Ok(url)
+            }
+            #[cfg(feature = "torrent")]
+            Resource::PendingTorrent { .. } => bail!("pending torrents can't be registered"),
+        }
+    }
+
+    /// Registers an image request along with resized variants served at
+    /// `/image/{id}/{width}x{height}`.
+    ///
+    /// Variants are resized lazily on their first fetch and cached, so they stay registered
+    /// until they expire instead of being consumed by the first request.
+    #[cfg(feature = "image-resize")]
+    #[instrument(skip_all, fields(resource_id = %id))]
+    pub async fn insert_image_variants(
+        &self,
+        id: String,
+        req: Box<HttpRequest>,
+        sizes: &[ImageSize],
+    ) -> anyhow::Result<ImageVariants> {
+        id.parse::<ResourceId>()?;
+
+        let mime_type = self
+            .mime_detector
+            .detect(&self.http_client, &self.with_credentials(&req))
+            .await?
+            .ok_or(UndetectedMediaType)?;
+
+        if !is_allowed(&self.image_types, &mime_type) {
+            return Err(UnsupportedMediaType(mime_type).into());
+        }
+
+        let mut variants = std::collections::HashMap::with_capacity(sizes.len());
+        for size in sizes {
+            let url = self.base_url.join(&format!("image/{id}/{size}"))?;
+            let resource = Resource::Http(req.clone());
+            self.save(
+                format!("{id}/{size}"),
+                resource,
+                Some(mime_type.clone()),
+                self.ttl,
+            )
+            .await?;
+            variants.insert(*size, url);
+        }
+
+        let original = self.base_url.join(&format!("image/{id}"))?;
+        self.save(id, Resource::Http(req), Some(mime_type), self.ttl)
+            .await?;
+
+        Ok(ImageVariants {
+            original,
+            sizes: variants,
+        })
+    }
+
+    /// Tells whether an HLS request is a live stream, i.e. its media playlist has no
+    /// `#EXT-X-ENDLIST`. For a master playlist, its first variant is checked.
+    ///
+    /// This is best-effort, anything else, including failures, playlists over
+    /// [`MAX_PROBED_PLAYLIST_BYTES`] and probes taking longer than [`LIVE_PROBE_TIMEOUT`], is
+    /// considered VOD.
+    pub async fn probe_live_stream(&self, req: &HttpRequest) -> bool {
+        if !crate::hls::is_playlist_uri(req.uri()) {
+            return false;
+        }
+
+        tokio::time::timeout(LIVE_PROBE_TIMEOUT, self.probe_playlists(req))
+            .await
+            .unwrap_or_else(|_| {
+                tracing::debug!("Live stream probe timed out, assuming VOD");
+                false
+            })
+    }
+
+    async fn probe_playlists(&self, req: &HttpRequest) -> bool {
+        let Some(playlist) = self.fetch_text(req.clone()).await else {
+            return false;
+        };
+
+        let Some(variant) = crate::hls::first_variant(&playlist) else {
+            return crate::hls::is_live(&playlist);
+        };
+
+        let Some(uri) = Url::parse(&req.uri().to_string())
+            .and_then(|base| base.join(variant))
+            .ok()
+            .and_then(|url| url.as_str().parse().ok())
+        else {
+            return false;
+        };
+
+        let mut variant_req = req.clone();
+        *variant_req.uri_mut() = uri;
+        self.fetch_text(variant_req)
+            .await
+            .is_some_and(|playlist| crate::hls::is_live(&playlist))
+    }
+
+    async fn fetch_text(&self, req: HttpRequest) -> Option<String> {
+        use crate::utils::{HopByHopHeadersExt, IntoReqwestRequest};
+
+        let mut req = self.with_credentials(&req);
+        req.headers_mut().remove_hop_by_hop_headers();
+
+        let request = req.into_reqwest_request(self.http_client.clone()).ok()?;
+        let mut res = self.http_client.execute(request).await.ok()?;
+        if !res.status().is_success() {
+            return None;
+        }
+
+        // A truncated playlist would look live for lack of its end tag, so it's given up on.
+        let mut buf = Vec::new();
+        while let Some(chunk) = res.chunk().await.ok()? {
+            if buf.len() + chunk.len() > MAX_PROBED_PLAYLIST_BYTES {
+                return None;
+            }
+            buf.extend_from_slice(&chunk);
+        }
+
+        String::from_utf8(buf).ok()
+    }
+
+    /// Fetches the start of an image and reads its dimensions from the header.
+    ///
+    /// This is best-effort, any failure along the way yields `None`.
+    #[cfg(feature = "image-dimensions")]
+    pub async fn probe_image_dimensions(&self, req: &HttpRequest) -> Option<(u32, u32)> {
+        use crate::utils::{HopByHopHeadersExt, IntoReqwestRequest};
+
+        const PROBE_BYTES: usize = 64 * 1024;
+
+        let mut req = self.with_credentials(req);
+        req.headers_mut().remove_hop_by_hop_headers();
+        req.headers_mut().insert(
+            http::header::RANGE,
+            http::HeaderValue::from_str(&format!("bytes=0-{}", PROBE_BYTES - 1)).ok()?,
+        );
+
+        let request = req.into_reqwest_request(self.http_client.clone()).ok()?;
+        let mut res = self.http_client.execute(request).await.ok()?;
+        if !res.status().is_success() {
+            return None;
+        }
+
+        let mut buf = Vec::new();
+        while buf.len() < PROBE_BYTES {
+            let Some(chunk) = res.chunk().await.ok()? else {
+                break;
+            };
+            buf.extend_from_slice(&chunk);
+        }
+
+        crate::imaging::dimensions(&buf)
+    }
+
+    pub async fn get(&self, id: &str) -> Option<Resource> {
+        match self.backend.get(id).await {
+            Ok(stored) => {
+                self.counters.record_lookup(stored.is_some());
+                stored.map(|stored| stored.resource)
+            }
+            Err(err) => {
+                tracing::warn!("Failed to get resource {id}: {err:#}");
+                None
+            }
+        }
+    }
+
+    pub async fn remove(&self, id: &str) -> Option<Resource> {
+        self.remove_with_mime(id)
+            .await
+            .map(|(resource, _)| resource)
+    }
+
+    /// Removes a resource along with the MIME type detected when it was registered.
+    pub(crate) async fn remove_with_mime(&self, id: &str) -> Option<(Resource, Option<Mime>)> {
+        match self.backend.remove(id).await {
+            Ok(stored) => {
+                self.counters.record_lookup(stored.is_some());
+                stored.map(|stored| (stored.resource, stored.mime))
+            }
+            Err(err) => {
+                tracing::warn!("Failed to remove resource {id}: {err:#}");
+                None
+            }
+        }
+    }
+
+    /// Persists a torrent whose playlist was served, so the stream URLs it lists still resolve
+    /// after a restart when the backend outlives the process. It expires along with resources.
+    #[cfg(feature = "torrent")]
+    pub(crate) async fn save_pending_torrent(
+        &self,
+        id: &str,
+        source: TorrentSource,
+        options: Option<AddTorrentOptions>,
+    ) -> anyhow::Result<()> {
+        let resource = StoredResource {
+            resource: Resource::PendingTorrent { source, options },
+            mime: None,
+        };
+        self.backend
+            .insert(pending_torrent_key(id), resource, self.ttl)
+            .await
+    }
+
+    #[cfg(feature = "torrent")]
+    pub(crate) async fn pending_torrent(
+        &self,
+        id: &str,
+    ) -> Option<(TorrentSource, Option<AddTorrentOptions>)> {
+        match self.backend.get(&pending_torrent_key(id)).await {
+            Ok(Some(StoredResource {
+                resource: Resource::PendingTorrent { source, options },
+                ..
+            })) => Some((source, options)),
+            Ok(_) => None,
+            Err(err) => {
+                tracing::warn!("Failed to get pending torrent {id}: {err:#}");
+                None
+            }
+        }
+    }
+
+    #[cfg(feature = "torrent")]
+    pub(crate) async fn remove_pending_torrent(&self, id: &str) {
+        if let Err(err) = self.backend.remove(&pending_torrent_key(id)).await {
+            tracing::warn!("Failed to remove pending torrent {id}: {err:#}");
+        }
+    }
+
+    /// Drops every registered resource.
+    pub async fn clear(&self) -> anyhow::Result<()> {
+        self.backend.clear().await
+    }
+
+    /// Returns lookup counts along with the expirations and size reported by the backend.
+    pub async fn stats(&self) -> CacheStats {
+        let current_size = self.backend.count().await.unwrap_or_else(|err| {
+            tracing::warn!("Failed to count resources: {err:#}");
+            0
+        });
+
+        CacheStats {
+            evictions: self.backend.evictions(),
+            ..self.counters.snapshot(current_size)
+        }
+    }
+}
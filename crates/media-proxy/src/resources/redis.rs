@@ -0,0 +1,153 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use redis::{AsyncCommands, aio::MultiplexedConnection};
+
+use super::{
+    ResourceBackend, StoredResource,
+    encoding::{decode, encode},
+};
+
+/// Keeps resources in Redis, so that every proxy pointing to the same server can serve the
+/// resources registered on any of them.
+///
+/// Stored ids are also indexed in a sorted set by expiration time, so the store capacity can be
+/// enforced without scanning every key.
+pub struct RedisBackend {
+    connection: MultiplexedConnection,
+    prefix: String,
+}
+
+impl RedisBackend {
+    /// Connects to Redis, storing resources under keys starting with `prefix`.
+    pub async fn connect(client: &redis::Client, prefix: impl Into<String>) -> Result<Self> {
+        Ok(Self {
+            connection: client.get_multiplexed_async_connection().await?,
+            prefix: prefix.into(),
+        })
+    }
+
+    fn key(&self, id: &str) -> String {
+        format!("{}{}", self.prefix, id)
+    }
+
+    /// Sorted set of the stored ids, scored by when they expire. Resource ids can't contain
+    /// `#`, and the key is under the prefix so clearing the store drops it too.
+    fn index_key(&self) -> String {
+        format!("{}#index", self.prefix)
+    }
+
+    /// Returns one page of the keys under the prefix, along with the cursor of the next one.
+    async fn scan(
+        &self,
+        conn: &mut MultiplexedConnection,
+        cursor: u64,
+    ) -> Result<(u64, Vec<String>)> {
+        Ok(redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg(format!("{}*", self.prefix))
+            .arg("COUNT")
+            .arg(1000)
+            .query_async(conn)
+            .await?)
+    }
+}
+
+#[async_trait::async_trait]
+impl ResourceBackend for RedisBackend {
+    async fn get(&self, id: &str) -> Result<Option<StoredResource>> {
+        let mut conn = self.connection.clone();
+        let value: Option<Vec<u8>> = conn.get(self.key(id)).await?;
+        value.map(|v| decode(&v)).transpose()
+    }
+
+    async fn insert(
+        &self,
+        id: String,
+        resource: StoredResource,
+        ttl: Option<Duration>,
+    ) -> Result<()> {
+        let mut conn = self.connection.clone();
+        let value = encode(&resource)?;
+
+        let mut set = redis::cmd("SET");
+        set.arg(self.key(&id)).arg(value);
+        let expires_at = match ttl {
+            Some(ttl) => {
+                let ttl_ms = ttl.as_millis().max(1) as u64;
+                set.arg("PX").arg(ttl_ms);
+                (now_ms() + ttl_ms).to_string()
+            }
+            None => "+inf".to_owned(),
+        };
+        redis::pipe()
+            .atomic()
+            .add_command(set)
+            .ignore()
+            .cmd("ZADD")
+            .arg(self.index_key())
+            .arg(expires_at)
+            .arg(id)
+            .ignore()
+            .query_async::<()>(&mut conn)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn remove(&self, id: &str) -> Result<Option<StoredResource>> {
+        let mut conn = self.connection.clone();
+        // GETDEL keeps resources one-shot when several proxies race for the same id.
+        let (value,): (Option<Vec<u8>>,) = redis::pipe()
+            .atomic()
+            .cmd("GETDEL")
+            .arg(self.key(id))
+            .cmd("ZREM")
+            .arg(self.index_key())
+            .arg(id)
+            .ignore()
+            .query_async(&mut conn)
+            .await?;
+        value.map(|v| decode(&v)).transpose()
+    }
+
+    async fn clear(&self) -> Result<()> {
+        let mut conn = self.connection.clone();
+        let mut cursor = 0u64;
+        loop {
+            let (next, keys) = self.scan(&mut conn, cursor).await?;
+            if !keys.is_empty() {
+                conn.del::<_, ()>(keys).await?;
+            }
+            if next == 0 {
+                return Ok(());
+            }
+            cursor = next;
+        }
+    }
+
+    async fn count(&self) -> Result<usize> {
+        let mut conn = self.connection.clone();
+        // Expired keys are dropped by Redis on its own, their index entries are dropped here.
+        let (len,): (usize,) = redis::pipe()
+            .atomic()
+            .cmd("ZREMRANGEBYSCORE")
+            .arg(self.index_key())
+            .arg("-inf")
+            .arg(now_ms())
+            .ignore()
+            .cmd("ZCARD")
+            .arg(self.index_key())
+            .query_async(&mut conn)
+            .await?;
+        Ok(len)
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
@@ -0,0 +1,67 @@
+use std::sync::Arc;
+
+use axum::{Json, extract::State};
+use http::StatusCode;
+use serde::Serialize;
+
+use crate::{ServerState, error::Error};
+
+/// How long the readiness check waits for the torrent backend to answer.
+#[cfg(feature = "torrent")]
+const BACKEND_CHECK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthReport {
+    status: &'static str,
+    torrent_enabled: bool,
+    cache_sizes: CacheSizes,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CacheSizes {
+    resources: usize,
+    #[cfg(feature = "imaging")]
+    images: usize,
+}
+
+/// Liveness of the proxy, answering as long as it serves requests at all.
+pub async fn handle_health_request(State(state): State<Arc<ServerState>>) -> Json<HealthReport> {
+    #[cfg(feature = "torrent")]
+    let torrent_enabled = state.torrent_backend.is_some();
+    #[cfg(not(feature = "torrent"))]
+    let torrent_enabled = false;
+
+    Json(HealthReport {
+        status: "ok",
+        torrent_enabled,
+        cache_sizes: CacheSizes {
+            resources: state.resource_store.stats().await.current_size,
+            #[cfg(feature = "imaging")]
+            images: state.image_cache.stats().await.current_size,
+        },
+    })
+}
+
+/// Readiness of the proxy, failing with a `503` while the configured torrent backend doesn't
+/// answer.
+pub async fn handle_ready_request(
+    State(state): State<Arc<ServerState>>,
+) -> Result<StatusCode, Error> {
+    #[cfg(feature = "torrent")]
+    if let Some(backend) = &state.torrent_backend {
+        match tokio::time::timeout(BACKEND_CHECK_TIMEOUT, backend.health()).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(err)) => {
+                tracing::warn!("Torrent backend is unhealthy: {err:#}");
+                return Err(Error::TorrentBackendUnavailable);
+            }
+            Err(_) => return Err(Error::TorrentBackendUnavailable),
+        }
+    }
+    #[cfg(not(feature = "torrent"))]
+    let _ = state;
+
+    Ok(StatusCode::OK)
+}
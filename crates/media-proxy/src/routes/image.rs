@@ -1,27 +1,70 @@
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
 
 use axum::{
     body::Body,
     extract::{Path, Request, State},
     response::Response,
 };
-use http::header::HOST;
+use futures_util::StreamExt;
+use http::{
+    HeaderMap, HeaderValue, StatusCode,
+    header::{ETAG, HOST, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+};
+use tracing::instrument;
 
 use crate::{
     ServerState,
     error::Error,
     resources::Resource,
-    utils::{HopByHopHeadersExt, IntoReqwestRequest},
+    utils::{
+        ContentTypeExt, HopByHopHeadersExt, HostCredentialsExt, IntoReqwestRequest, etag_matches,
+    },
 };
 
+#[instrument(skip_all, fields(resource_id = %resource_id))]
 pub async fn handle_image_request(
     State(state): State<Arc<ServerState>>,
     Path(resource_id): Path<String>,
     incoming_request: Request<Body>,
 ) -> Result<Response, Error> {
-    let resource = state
+    let resource_id = super::parse_resource_id(&resource_id)?;
+
+    // Registered resources are consumed by their first fetch, so revalidations are answered
+    // here from the validators upstream sent along with the image.
+    if let Some(validators) = state.image_validators.get(&resource_id)
+        && validators.is_fresh(incoming_request.headers())
+    {
+        return Ok(validators.not_modified());
+    }
+
+    #[cfg(feature = "image-transcode")]
+    let transcode_format = incoming_request
+        .headers()
+        .get(http::header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|accept| crate::imaging::preferred_format(accept, state.image_transcode_avif));
+
+    #[cfg(feature = "image-transcode")]
+    if let Some(format) = transcode_format {
+        let key = crate::imaging::transcode_key(&resource_id, format);
+        if let Some(image) = state.image_cache.get(&key).await {
+            return Ok(transcoded_response(&image, incoming_request.headers()));
+        }
+    }
+
+    #[cfg(feature = "image-resize")]
+    if state.image_max_dimension.is_some()
+        && let Some(image) = state.image_cache.get(&downscaled_key(&resource_id)).await
+    {
+        return Ok(image.respond(incoming_request.headers()));
+    }
+
+    let (resource, mime) = state
         .resource_store
-        .remove(&resource_id)
+        .remove_with_mime(&resource_id)
         .await
         .ok_or(Error::NotFound)?;
 
@@ -31,7 +74,7 @@ pub async fn handle_image_request(
     };
 
     for (name, value) in incoming_request.headers().iter() {
-        if name == HOST {
+        if [HOST, IF_NONE_MATCH, IF_MODIFIED_SINCE].contains(name) {
             continue;
         }
         stored_request
@@ -40,9 +83,15 @@ pub async fn handle_image_request(
     }
 
     stored_request.headers_mut().remove_hop_by_hop_headers();
+    stored_request.apply_host_credentials(&state.host_credentials);
+
+    let inflight = state.inflight.register(&resource_id);
 
     let request = stored_request.into_reqwest_request(state.http_client.clone())?;
-    let response = state.http_client.execute(request).await?;
+    let response = tokio::select! {
+        response = state.http_client.execute(request) => response?,
+        _ = inflight.token().cancelled() => return Err(Error::Cancelled),
+    };
 
     let status = response.status();
     if !status.is_success() {
@@ -51,8 +100,87 @@ pub async fn handle_image_request(
 
     let mut headers = response.headers().clone();
     headers.remove_hop_by_hop_headers();
+    headers.fallback_content_type(mime.as_ref());
+    let validators = Validators::from_upstream(&headers, &resource_id);
+    headers.insert(ETAG, validators.etag.clone());
+    state
+        .image_validators
+        .insert(resource_id.to_string(), validators);
 
-    let stream = response.bytes_stream();
+    #[cfg(feature = "image-transcode")]
+    let transcode_format = transcode_format.filter(|format| {
+        !headers
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.starts_with(format.to_mime_type()))
+    });
+
+    #[cfg(any(feature = "image-resize", feature = "image-transcode"))]
+    {
+        #[cfg(feature = "image-resize")]
+        let max_dimension = state.image_max_dimension;
+        #[cfg(not(feature = "image-resize"))]
+        let max_dimension: Option<u32> = None;
+        #[cfg(feature = "image-transcode")]
+        let transcoding = transcode_format.is_some();
+        #[cfg(not(feature = "image-transcode"))]
+        let transcoding = false;
+
+        if max_dimension.is_some() || transcoding {
+            let bytes = tokio::select! {
+                bytes = response.bytes() => bytes?,
+                _ = inflight.token().cancelled() => return Err(Error::Cancelled),
+            };
+
+            // Downscaling goes first so transcoded images stay within the maximum dimension.
+            #[cfg(feature = "image-resize")]
+            let downscaled = match max_dimension {
+                Some(max) => downscale(&bytes, max).await,
+                None => None,
+            };
+            #[cfg(not(feature = "image-resize"))]
+            let downscaled: Option<crate::imaging::CachedImage> = None;
+
+            #[cfg(feature = "image-transcode")]
+            if let Some(format) = transcode_format {
+                let source = downscaled
+                    .as_ref()
+                    .map_or_else(|| bytes.clone(), |image| image.bytes.clone());
+                let transcoded =
+                    crate::imaging::blocking(move || crate::imaging::transcode(&source, format))
+                        .await;
+
+                match transcoded {
+                    Ok(image) => {
+                        let key = crate::imaging::transcode_key(&resource_id, format);
+                        let response = transcoded_response(&image, incoming_request.headers());
+                        state.image_cache.insert(key, image).await;
+                        return Ok(response);
+                    }
+                    Err(err) => {
+                        tracing::warn!("Not transcoding the image, transcoding failed: {err}")
+                    }
+                }
+            }
+
+            #[cfg(feature = "image-resize")]
+            if let Some(image) = downscaled {
+                let response = image.respond(incoming_request.headers());
+                state
+                    .image_cache
+                    .insert(downscaled_key(&resource_id), image)
+                    .await;
+                return Ok(response);
+            }
+
+            let mut response = Response::new(Body::from(bytes));
+            *response.status_mut() = status;
+            *response.headers_mut() = headers;
+            return Ok(response);
+        }
+    }
+
+    let stream = response.bytes_stream().take_until(inflight.cancelled());
     let body = Body::from_stream(stream);
 
     let mut response = Response::new(body);
@@ -61,3 +189,285 @@ pub async fn handle_image_request(
 
     Ok(response)
 }
+
+/// Validators are forgotten past this many images, oldest first.
+const MAX_VALIDATORS: usize = 1024;
+
+/// The validators an image was served with.
+#[derive(Debug, Clone)]
+pub(crate) struct Validators {
+    etag: HeaderValue,
+    last_modified: Option<HeaderValue>,
+}
+
+impl Validators {
+    /// Takes the validators upstream sent, with an entity tag derived from the resource id when
+    /// it sent none. The image behind a resource never changes once it is registered.
+    fn from_upstream(headers: &HeaderMap, resource_id: &str) -> Self {
+        Self {
+            etag: headers
+                .get(ETAG)
+                .cloned()
+                .unwrap_or_else(|| HeaderValue::from_str(&format!("\"{resource_id}\"")).unwrap()),
+            last_modified: headers.get(LAST_MODIFIED).cloned(),
+        }
+    }
+
+    /// Whether the client's copy of the image is current. `If-Modified-Since` is only looked
+    /// at without `If-None-Match`, and only against the `Last-Modified` upstream sent.
+    fn is_fresh(&self, request_headers: &HeaderMap) -> bool {
+        if let Some(if_none_match) = request_headers.get(IF_NONE_MATCH) {
+            return self
+                .etag
+                .to_str()
+                .is_ok_and(|etag| etag_matches(if_none_match, etag));
+        }
+
+        let parse = |value: &HeaderValue| {
+            value
+                .to_str()
+                .ok()
+                .and_then(|v| httpdate::parse_http_date(v).ok())
+        };
+        match (
+            request_headers.get(IF_MODIFIED_SINCE).and_then(parse),
+            self.last_modified.as_ref().and_then(parse),
+        ) {
+            (Some(since), Some(modified)) => modified <= since,
+            _ => false,
+        }
+    }
+
+    fn not_modified(&self) -> Response {
+        let mut builder = Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header(ETAG, &self.etag);
+        if let Some(last_modified) = &self.last_modified {
+            builder = builder.header(LAST_MODIFIED, last_modified);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+}
+
+/// Validators of the images served so far, by resource id.
+#[derive(Default)]
+pub(crate) struct ImageValidators {
+    entries: Mutex<(HashMap<String, Validators>, VecDeque<String>)>,
+}
+
+impl ImageValidators {
+    fn get(&self, resource_id: &str) -> Option<Validators> {
+        self.entries.lock().unwrap().0.get(resource_id).cloned()
+    }
+
+    fn insert(&self, resource_id: String, validators: Validators) {
+        let (entries, order) = &mut *self.entries.lock().unwrap();
+        if entries.insert(resource_id.clone(), validators).is_none() {
+            order.push_back(resource_id);
+        }
+        if order.len() > MAX_VALIDATORS
+            && let Some(oldest) = order.pop_front()
+        {
+            entries.remove(&oldest);
+        }
+    }
+}
+
+/// Downscales an image to fit within `max` on both sides, `None` when it already fits or
+/// couldn't be downscaled.
+#[cfg(feature = "image-resize")]
+async fn downscale(bytes: &bytes::Bytes, max: u32) -> Option<crate::imaging::CachedImage> {
+    use crate::imaging::{self, ImageSize};
+
+    let oversized =
+        imaging::dimensions(bytes).is_some_and(|(width, height)| width > max || height > max);
+    if !oversized {
+        return None;
+    }
+
+    let source = bytes.clone();
+    imaging::blocking(move || imaging::resize(&source, ImageSize::new(max, max)))
+        .await
+        .inspect_err(|err| tracing::warn!("Serving the original image, downscaling failed: {err}"))
+        .ok()
+}
+
+/// Cache key of an image downscaled to the configured maximum dimension.
+#[cfg(feature = "image-resize")]
+fn downscaled_key(resource_id: &str) -> String {
+    format!("{resource_id}@max")
+}
+
+#[cfg(feature = "image-transcode")]
+fn transcoded_response(
+    image: &crate::imaging::CachedImage,
+    request_headers: &http::HeaderMap,
+) -> Response {
+    let mut response = image.respond(request_headers);
+    response
+        .headers_mut()
+        .insert(http::header::VARY, http::HeaderValue::from_static("accept"));
+    response
+}
+
+#[cfg(feature = "image-resize")]
+#[instrument(skip_all, fields(resource_id = %resource_id, size = %size))]
+pub async fn handle_image_variant_request(
+    State(state): State<Arc<ServerState>>,
+    Path((resource_id, size)): Path<(String, String)>,
+    request_headers: http::HeaderMap,
+) -> Result<Response, Error> {
+    use crate::imaging::{self, ImageSize};
+
+    let size = size.parse::<ImageSize>().map_err(|_| Error::NotFound)?;
+    let resource_id = super::parse_resource_id(&resource_id)?;
+    let key = format!("{resource_id}/{size}");
+
+    let Some(resource) = state.resource_store.get(&key).await else {
+        state.image_cache.remove(&key).await;
+        return Err(Error::NotFound);
+    };
+
+    if let Some(image) = state.image_cache.get(&key).await {
+        return Ok(image.respond(&request_headers));
+    }
+
+    #[allow(irrefutable_let_patterns)]
+    let Resource::Http(mut stored_request) = resource else {
+        return Err(Error::InvalidResourceKind);
+    };
+
+    stored_request.headers_mut().remove_hop_by_hop_headers();
+    stored_request.apply_host_credentials(&state.host_credentials);
+
+    let request = stored_request.into_reqwest_request(state.http_client.clone())?;
+    let response = state.http_client.execute(request).await?;
+
+    let status = response.status();
+    if !status.is_success() {
+        return Err(Error::RemoteServer(status));
+    }
+
+    let bytes = response.bytes().await?;
+    let image = imaging::blocking(move || imaging::resize(&bytes, size)).await?;
+
+    let response = image.respond(&request_headers);
+    state.image_cache.insert(key, image).await;
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{
+        HeaderMap, HeaderValue,
+        header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    };
+
+    use super::Validators;
+
+    fn headers(pairs: &[(http::HeaderName, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.clone(), HeaderValue::from_static(value)))
+            .collect()
+    }
+
+    #[test]
+    fn if_modified_since_is_compared_with_upstream_last_modified() {
+        let upstream = headers(&[
+            (ETAG, "\"upstream\""),
+            (LAST_MODIFIED, "Wed, 21 Oct 2015 07:28:00 GMT"),
+        ]);
+        let validators = Validators::from_upstream(&upstream, "id");
+
+        assert!(validators.is_fresh(&headers(&[(IF_NONE_MATCH, "\"upstream\"")])));
+        assert!(!validators.is_fresh(&headers(&[(IF_NONE_MATCH, "\"id\"")])));
+        assert!(validators.is_fresh(&headers(&[(
+            IF_MODIFIED_SINCE,
+            "Wed, 21 Oct 2015 07:28:00 GMT"
+        )])));
+        assert!(!validators.is_fresh(&headers(&[(
+            IF_MODIFIED_SINCE,
+            "Tue, 20 Oct 2015 07:28:00 GMT"
+        )])));
+
+        let without_date = Validators::from_upstream(&HeaderMap::new(), "id");
+        assert!(!without_date.is_fresh(&headers(&[(
+            IF_MODIFIED_SINCE,
+            "Wed, 21 Oct 2015 07:28:00 GMT"
+        )])));
+    }
+    /// Reads the size of an AVIF image from its `ispe` property.
+    #[cfg(all(feature = "image-resize", feature = "image-transcode"))]
+    fn avif_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+        let start = bytes.windows(4).position(|window| window == b"ispe")? + 8;
+        let field = |offset: usize| {
+            let bytes = bytes.get(start + offset..start + offset + 4)?;
+            Some(u32::from_be_bytes(bytes.try_into().ok()?))
+        };
+        Some((field(0)?, field(4)?))
+    }
+
+    #[cfg(all(feature = "image-resize", feature = "image-transcode"))]
+    #[tokio::test]
+    async fn transcoded_images_are_downscaled_first() {
+        use std::io::Cursor;
+
+        use axum::{Router, routing::get};
+        use bytes::Bytes;
+        use http::header::{ACCEPT, CONTENT_TYPE};
+        use image::{DynamicImage, ImageFormat};
+        use tokio::net::TcpListener;
+
+        use crate::{MediaProxy, MediaProxyConfig};
+
+        let mut png = Cursor::new(Vec::new());
+        DynamicImage::new_rgb8(64, 32)
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+        let png = Bytes::from(png.into_inner());
+
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let app = Router::new().route(
+            "/image.png",
+            get(move || async move { ([(CONTENT_TYPE, "image/png")], png) }),
+        );
+        tokio::spawn(async move { axum::serve(upstream, app).await });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = MediaProxy::new(
+            listener.local_addr().unwrap(),
+            reqwest::Client::new(),
+            MediaProxyConfig {
+                image_max_dimension: Some(16),
+                image_transcode_avif: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        // Requests without headers are handed back as is, a header makes it go through the proxy.
+        let request = http::Request::get(format!("http://{upstream_addr}/image.png"))
+            .header(http::header::REFERER, "http://example.com/")
+            .body(None)
+            .unwrap();
+        let url = proxy
+            .resource_store()
+            .insert_with_mime_hint("image".into(), Box::new(request), "image/png")
+            .await
+            .unwrap();
+        tokio::spawn(async move { proxy.run(listener).await });
+
+        let response = reqwest::Client::new()
+            .get(url)
+            .header(ACCEPT, "image/avif,image/webp,*/*")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[CONTENT_TYPE], "image/avif");
+
+        let body = response.bytes().await.unwrap();
+        assert_eq!(avif_dimensions(&body), Some((16, 8)));
+    }
+}
@@ -0,0 +1,24 @@
+use std::sync::Arc;
+
+use axum::{extract::State, response::IntoResponse};
+use http::header::CONTENT_TYPE;
+
+use crate::{ServerState, error::Error};
+
+/// Metrics of the exporter set with [`crate::MediaProxy::set_metrics_exporter`], in the
+/// Prometheus text format.
+pub async fn handle_metrics_request(
+    State(state): State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, Error> {
+    let exporter = state
+        .metrics_exporter
+        .read()
+        .unwrap()
+        .clone()
+        .ok_or(Error::NotFound)?;
+
+    Ok((
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        exporter.export(),
+    ))
+}
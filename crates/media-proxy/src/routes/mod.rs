@@ -1,9 +1,20 @@
+mod health;
 mod image;
+mod metrics;
 #[cfg(feature = "torrent")]
 mod torrent;
 mod video;
 
+pub use health::*;
 pub use image::*;
+pub use metrics::*;
 #[cfg(feature = "torrent")]
 pub use torrent::*;
 pub use video::*;
+
+use crate::{error::Error, resources::ResourceId};
+
+/// Parses the resource id of a route, treating a malformed one like an unknown one.
+fn parse_resource_id(id: &str) -> Result<ResourceId, Error> {
+    id.parse().map_err(|_| Error::NotFound)
+}
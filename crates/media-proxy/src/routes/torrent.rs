@@ -1,18 +1,50 @@
 use std::{sync::Arc, time::Duration};
 
 use axum::{
+    Json,
     body::Body,
     extract::{Path, State},
     response::Response,
 };
-use http::{Request, StatusCode, header::CONTENT_TYPE, uri::Scheme};
+use futures_util::StreamExt;
+use http::{
+    HeaderMap, HeaderValue, Request, StatusCode,
+    header::{ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, RANGE},
+};
+use tokio::{sync::OnceCell, time::Instant};
+use tracing::instrument;
+
+use crate::{
+    ServerState,
+    error::Error,
+    resources::Resource,
+    torrent::{
+        AddTorrentOptions, NotReady, TorrentBackend, TorrentFile, TorrentSource, TorrentStats,
+        UnavailableDataPolicy,
+    },
+};
 
-use crate::{ServerState, error::Error, resources::Resource, torrent::AddTorrentOptions};
+const INITIAL_READY_DELAY: Duration = Duration::from_millis(100);
+const MAX_READY_DELAY: Duration = Duration::from_secs(2);
 
+/// A torrent listed through its m3u playlist, added to the backend on its first stream request.
+pub(crate) enum PendingTorrent {
+    Waiting {
+        source: TorrentSource,
+        options: Option<AddTorrentOptions>,
+        /// Backend id of the torrent once added, shared by the requests waiting on the add.
+        starting: Arc<OnceCell<String>>,
+    },
+    Started(String),
+}
+
+#[instrument(skip_all, fields(resource_id = %resource_id))]
 pub async fn handle_torrent_request(
     State(state): State<Arc<ServerState>>,
     Path(resource_id): Path<String>,
 ) -> Result<Response, Error> {
+    let resource_id = super::parse_resource_id(&resource_id)?;
+
     let resource = state
         .resource_store
         .remove(&resource_id)
@@ -24,44 +56,87 @@ pub async fn handle_torrent_request(
         .as_ref()
         .ok_or(Error::TorrentSupportDisabled)?;
 
-    #[allow(irrefutable_let_patterns)]
     let Resource::Torrent(source) = resource else {
         return Err(Error::InvalidResourceKind);
     };
 
-    {
-        let mut current = state.current_torrent.write().await;
-        if let Some(torrent) = current.take() {
-            backend.cancel_torrent(&torrent.id).await.ok();
-        }
-    }
-
-    let options = if let Some(selector) = &state.torrent_file_selector {
-        let files = backend.list_files(&source).await?;
+    let files = backend.list_files(&source).await?;
+    let (mut files, mut options) = if let Some(selector) = &state.torrent_file_selector {
         let file_indices = selector.select(&files).await?;
-        Some(AddTorrentOptions { file_indices })
+        let files = files
+            .into_iter()
+            .filter(|file| file_indices.contains(&file.index))
+            .collect::<Vec<_>>();
+        (files, Some(AddTorrentOptions { file_indices }))
     } else {
-        None
+        (files, None)
     };
 
-    let added = backend.add_torrent(source, options).await?;
-
+    if let Some(max) = state.torrent_max_files
+        && files.len() > max
     {
-        let mut current = state.current_torrent.write().await;
-        *current = Some(added.clone());
+        tracing::warn!(
+            "Torrent has {} files, only keeping the first {max}",
+            files.len()
+        );
+        files.truncate(max);
+        options = Some(AddTorrentOptions {
+            file_indices: files.iter().map(|file| file.index).collect(),
+        });
+    }
+
+    // Players often fetch the playlist well before playback, the torrent is only added once
+    // one of its streams is requested. It is kept in the resource backend as well, so the
+    // stream URLs still resolve after a restart.
+    if !state.torrent_concurrent_playback {
+        clear_pending_torrents(&state).await;
     }
+    state
+        .resource_store
+        .save_pending_torrent(&resource_id, source.clone(), options.clone())
+        .await?;
+    state.pending_torrents.write().await.insert(
+        resource_id.to_string(),
+        PendingTorrent::Waiting {
+            source,
+            options,
+            starting: Arc::default(),
+        },
+    );
+
+    // Resolutions are only worth telling apart when the files come in several.
+    let resolutions = files
+        .iter()
+        .map(|file| crate::torrent::resolution_hint(&file.name))
+        .collect::<Vec<_>>();
+    let mut distinct = resolutions.iter().flatten().collect::<Vec<_>>();
+    distinct.sort();
+    distinct.dedup();
+    let tag_resolutions = distinct.len() > 1;
 
     let mut m3u = String::from("#EXTM3U\n");
-    for file in added.files {
-        let url = format!(
-            "{}://{}/torrent/{}/stream/{}",
-            Scheme::HTTP,
-            state.addr,
-            added.id,
-            file.index
-        );
+    for (file, resolution) in files.into_iter().zip(resolutions) {
+        // The playlist is served at `torrent/{id}`, so its streams are under `{id}/stream/`
+        // relative to it.
+        let path = format!("{}/stream/{}", resource_id, file.index);
+        let url = if state.torrent_relative_stream_urls {
+            path
+        } else {
+            state
+                .base_url
+                .join(&format!("torrent/{path}"))
+                .map_err(anyhow::Error::from)?
+                .to_string()
+        };
 
-        m3u.push_str(&format!("#EXTINF:-1,{}\n{}\n", file.name, url));
+        // Durations aren't known until the torrent is added, on the first stream request.
+        match resolution.filter(|_| tag_resolutions) {
+            Some(resolution) => m3u.push_str(&format!(
+                "#EXTINF:-1 resolution=\"{resolution}\",{}\n{url}\n",
+                file.name
+            )),
+            None => m3u.push_str(&format!("#EXTINF:-1,{}\n{url}\n", file.name)),
+        }
     }
 
     let response = Response::builder()
@@ -73,6 +148,7 @@ pub async fn handle_torrent_request(
     Ok(response)
 }
 
+#[instrument(skip_all, fields(torrent_id = %torrent_id, file_index = file_index))]
 pub async fn handle_torrent_stream_request(
     State(state): State<Arc<ServerState>>,
     Path((torrent_id, file_index)): Path<(String, usize)>,
@@ -83,9 +159,34 @@ pub async fn handle_torrent_stream_request(
         .as_ref()
         .ok_or(Error::TorrentSupportDisabled)?;
 
+    let torrent_id = start_pending_torrent(&state, &torrent_id)
+        .await?
+        .unwrap_or(torrent_id);
+
+    #[cfg(feature = "transcode")]
+    let transcode_target = crate::transcode::requested_target(incoming_request.uri())?;
+
     let (parts, _body) = incoming_request.into_parts();
+    // The whole file is needed to transcode it.
+    #[cfg(feature = "transcode")]
+    let parts = {
+        let mut parts = parts;
+        if transcode_target.is_some() {
+            parts.headers.remove(RANGE);
+        }
+        parts
+    };
+
+    if let Some(start) = range_start(&parts.headers)
+        && let Err(err) = backend.prioritize(&torrent_id, file_index, start).await
+    {
+        tracing::debug!("Failed to prioritize pieces at {start}: {err:#}");
+    }
 
-    // TODO: any better way to do this?
+    // The backend can't stream until the torrent metadata is known, poll with a growing delay
+    // until it is or the deadline passes.
+    let deadline = Instant::now() + state.torrent_ready_timeout;
+    let mut delay = INITIAL_READY_DELAY;
     loop {
         match backend
             .handle_stream_request(
@@ -95,16 +196,269 @@ pub async fn handle_torrent_stream_request(
             )
             .await
         {
-            Ok(resp) => return Ok(resp),
-            #[cfg(feature = "torrent-librqbit")]
-            Err(err)
-                if err.to_string().contains("initializing")
-                    || err.to_string().contains("metadata") =>
-            {
-                tokio::time::sleep(Duration::from_millis(200)).await;
-                continue;
+            Ok(mut resp) => {
+                for (name, value) in state.torrent_stream_headers.iter() {
+                    if [CONTENT_RANGE, CONTENT_LENGTH, ACCEPT_RANGES].contains(name) {
+                        continue;
+                    }
+                    resp.headers_mut().insert(name.clone(), value.clone());
+                }
+
+                #[cfg(feature = "archive")]
+                let entry_mime = crate::torrent::archive::requested_entry(&parts.uri)
+                    .and_then(|entry| mime_guess::from_path(entry).first());
+                #[cfg(not(feature = "archive"))]
+                let entry_mime = None;
+
+                if !resp.headers().contains_key(CONTENT_TYPE)
+                    && let Some(mime) = match entry_mime {
+                        Some(mime) => Some(mime),
+                        None => guess_file_mime(&state, &torrent_id, file_index).await,
+                    }
+                    && let Ok(value) = HeaderValue::from_str(mime.as_ref())
+                {
+                    resp.headers_mut().insert(CONTENT_TYPE, value);
+                }
+
+                if let Some(rate) = state.max_bytes_per_sec {
+                    resp = resp.map(|body| crate::throttle::throttle_body(body, rate));
+                }
+
+                #[cfg(feature = "transcode")]
+                if let Some(target) = transcode_target {
+                    return crate::transcode::transcode(
+                        resp.into_body(),
+                        target,
+                        &state.ffmpeg_path,
+                    );
+                }
+
+                if let UnavailableDataPolicy::FailFast { grace } = state.torrent_unavailable_data
+                    && is_seek(&parts.headers)
+                {
+                    return fail_fast(resp, grace).await;
+                }
+                return Ok(resp);
+            }
+            Err(err) if err.is::<NotReady>() => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(Error::TorrentNotReady);
+                }
+                tokio::time::sleep(delay.min(deadline - now)).await;
+                delay = (delay * 2).min(MAX_READY_DELAY);
             }
             Err(err) => return Err(err.into()),
         }
     }
 }
+
+/// Lists every file of a registered torrent without downloading it, so a UI can let the user
+/// pick files before playback. Neither consumes the registration nor applies the configured
+/// file selector.
+#[instrument(skip_all, fields(resource_id = %resource_id))]
+pub async fn handle_torrent_files_request(
+    State(state): State<Arc<ServerState>>,
+    Path(resource_id): Path<String>,
+) -> Result<Json<Vec<TorrentFile>>, Error> {
+    let resource_id = super::parse_resource_id(&resource_id)?;
+
+    let backend = state
+        .torrent_backend
+        .as_ref()
+        .ok_or(Error::TorrentSupportDisabled)?;
+
+    // Its m3u may have been fetched already, which moves it to the pending torrents.
+    restore_pending_torrent(&state, &resource_id).await;
+    let source = match state.resource_store.get(&resource_id).await {
+        Some(resource) => {
+            let Resource::Torrent(source) = resource else {
+                return Err(Error::InvalidResourceKind);
+            };
+            source
+        }
+        None => match state.pending_torrents.read().await.get(&*resource_id) {
+            Some(PendingTorrent::Waiting { source, .. }) => source.clone(),
+            _ => return Err(Error::NotFound),
+        },
+    };
+
+    Ok(Json(backend.list_files(&source).await?))
+}
+
+/// Download progress of the torrent behind `torrent_id`, which is either a backend id or the
+/// resource id its playlist was listed under. A torrent whose streams weren't requested yet
+/// has no progress and isn't found.
+#[instrument(skip_all, fields(torrent_id = %torrent_id))]
+pub async fn handle_torrent_stats_request(
+    State(state): State<Arc<ServerState>>,
+    Path(torrent_id): Path<String>,
+) -> Result<Json<TorrentStats>, Error> {
+    let backend = state
+        .torrent_backend
+        .as_ref()
+        .ok_or(Error::TorrentSupportDisabled)?;
+
+    let torrent_id = started_torrent_id(&state, &torrent_id)
+        .await
+        .ok_or(Error::NotFound)?;
+
+    Ok(Json(backend.download_stats(&torrent_id).await?))
+}
+
+/// Resolves a resource id listed through an m3u to the backend id of its torrent, returning
+/// `None` while it isn't started. Any other id is assumed to be a backend id already.
+pub(crate) async fn started_torrent_id(state: &ServerState, id: &str) -> Option<String> {
+    restore_pending_torrent(state, id).await;
+    match state.pending_torrents.read().await.get(id) {
+        Some(PendingTorrent::Started(torrent_id)) => Some(torrent_id.clone()),
+        Some(PendingTorrent::Waiting { .. }) => None,
+        None => Some(id.to_owned()),
+    }
+}
+
+/// Brings back the torrent listed under `id` from the resource backend when it isn't pending
+/// in this process, e.g. because its playlist was served before a restart.
+async fn restore_pending_torrent(state: &ServerState, id: &str) {
+    if state.pending_torrents.read().await.contains_key(id) {
+        return;
+    }
+
+    if let Some((source, options)) = state.resource_store.pending_torrent(id).await {
+        tracing::debug!(resource_id = %id, "Restored pending torrent");
+        state
+            .pending_torrents
+            .write()
+            .await
+            .entry(id.to_owned())
+            .or_insert(PendingTorrent::Waiting {
+                source,
+                options,
+                starting: Arc::default(),
+            });
+    }
+}
+
+/// Forgets the torrents listed under `ids`, including in the resource backend.
+pub(crate) async fn forget_pending_torrents(state: &ServerState, ids: &[String]) {
+    let mut pending = state.pending_torrents.write().await;
+    for id in ids {
+        pending.remove(id);
+    }
+    drop(pending);
+
+    for id in ids {
+        state.resource_store.remove_pending_torrent(id).await;
+    }
+}
+
+/// Forgets every listed torrent, including in the resource backend.
+pub(crate) async fn clear_pending_torrents(state: &ServerState) {
+    let ids = state
+        .pending_torrents
+        .read()
+        .await
+        .keys()
+        .cloned()
+        .collect::<Vec<_>>();
+    forget_pending_torrents(state, &ids).await;
+}
+
+/// Cancels the active torrents before another one is added, unless several may play at once.
+pub(crate) async fn make_room_for_torrent(state: &ServerState, backend: &dyn TorrentBackend) {
+    if state.torrent_concurrent_playback {
+        return;
+    }
+
+    for torrent in state.active_torrents.write().await.drain(..) {
+        backend.cancel_torrent(&torrent.id).await.ok();
+    }
+}
+
+/// Offset of the first byte of the `Range` a request asks for.
+fn range_start(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("bytes="))
+        .and_then(|v| v.split_once('-'))
+        .and_then(|(start, _)| start.parse::<u64>().ok())
+}
+
+/// Whether the request starts reading past the beginning of the file.
+fn is_seek(headers: &HeaderMap) -> bool {
+    range_start(headers).is_some_and(|start| start > 0)
+}
+
+/// Waits up to `grace` for the first chunk of `resp`, failing when the backend is still
+/// waiting for the data to be downloaded.
+async fn fail_fast(resp: Response, grace: Duration) -> Result<Response, Error> {
+    let (parts, body) = resp.into_parts();
+    let mut stream = body.into_data_stream();
+
+    let Ok(first) = tokio::time::timeout(grace, stream.next()).await else {
+        return Err(Error::TorrentDataUnavailable);
+    };
+
+    let body = Body::from_stream(futures_util::stream::iter(first).chain(stream));
+    Ok(Response::from_parts(parts, body))
+}
+
+/// Adds the pending torrent listed under `id`, replacing the currently active one unless
+/// concurrent playback is enabled, and returns its backend id. Returns `None` when `id` doesn't
+/// refer to a pending torrent.
+async fn start_pending_torrent(state: &ServerState, id: &str) -> Result<Option<String>, Error> {
+    restore_pending_torrent(state, id).await;
+    let (source, options, starting) = match state.pending_torrents.read().await.get(id) {
+        None => return Ok(None),
+        Some(PendingTorrent::Started(torrent_id)) => return Ok(Some(torrent_id.clone())),
+        Some(PendingTorrent::Waiting {
+            source,
+            options,
+            starting,
+        }) => (source.clone(), options.clone(), starting.clone()),
+    };
+
+    let backend = state
+        .torrent_backend
+        .as_ref()
+        .ok_or(Error::TorrentSupportDisabled)?;
+
+    // Adding a magnet can take a while, the map isn't locked meanwhile so other torrents keep
+    // streaming. Requests for this one wait on the same add.
+    let torrent_id = starting
+        .get_or_try_init(|| async {
+            make_room_for_torrent(state, backend.as_ref()).await;
+
+            let added = backend.add_torrent(source, options).await?;
+            tracing::debug!(resource_id = %id, torrent_id = %added.id, "Started pending torrent");
+
+            let torrent_id = added.id.clone();
+            state.active_torrents.write().await.push(added);
+            state.current_video.write().await.take();
+            Ok::<_, Error>(torrent_id)
+        })
+        .await?
+        .clone();
+
+    // The entry may have been dropped or listed again while the torrent was being added.
+    if let Some(entry) = state.pending_torrents.write().await.get_mut(id)
+        && matches!(entry, PendingTorrent::Waiting { starting: current, .. } if Arc::ptr_eq(current, &starting))
+    {
+        *entry = PendingTorrent::Started(torrent_id.clone());
+    }
+
+    Ok(Some(torrent_id))
+}
+
+/// Guesses a stream's MIME type from the file name of an active torrent.
+async fn guess_file_mime(
+    state: &ServerState,
+    torrent_id: &str,
+    file_index: usize,
+) -> Option<mime::Mime> {
+    let active = state.active_torrents.read().await;
+    let torrent = active.iter().find(|t| t.id == torrent_id)?;
+    let file = torrent.files.iter().find(|f| f.index == file_index)?;
+    mime_guess::from_path(&file.name).first()
+}
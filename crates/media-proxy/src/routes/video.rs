@@ -5,60 +5,113 @@ use axum::{
     extract::{Path, State},
     response::Response,
 };
+use futures_util::StreamExt;
+use http::{HeaderValue, StatusCode, header::ACCEPT_RANGES};
+use tracing::instrument;
 
 use crate::{
     ServerState,
     error::Error,
-    resources::Resource,
-    utils::{HopByHopHeadersExt, IntoReqwestRequest},
+    resources::{Resource, StoredResource},
+    utils::{ContentTypeExt, HopByHopHeadersExt, HostCredentialsExt, IntoReqwestRequest},
 };
 
+#[instrument(skip_all, fields(resource_id = %resource_id))]
 pub async fn handle_video_request(
     State(state): State<Arc<ServerState>>,
     Path(resource_id): Path<String>,
     incoming_request: axum::extract::Request,
 ) -> Result<Response, Error> {
-    let resource = state
-        .resource_store
-        .remove(&resource_id)
-        .await
-        .ok_or(Error::NotFound)?;
+    let resource_id = super::parse_resource_id(&resource_id)?;
+
+    #[cfg(feature = "transcode")]
+    let transcode_target = crate::transcode::requested_target(incoming_request.uri())?;
+
+    // Registrations are consumed by their first fetch, players seeking later on request the
+    // current video again with a `Range`.
+    let stored = match state.resource_store.remove_with_mime(&resource_id).await {
+        Some((resource, mime)) => StoredResource { resource, mime },
+        None => state
+            .current_video
+            .read()
+            .await
+            .as_ref()
+            .filter(|(id, _)| **id == *resource_id)
+            .map(|(_, stored)| stored.clone())
+            .ok_or(Error::NotFound)?,
+    };
 
     #[allow(irrefutable_let_patterns)]
-    let Resource::Http(mut stored_request) = resource else {
+    let Resource::Http(mut stored_request) = stored.resource.clone() else {
         return Err(Error::InvalidResourceKind);
     };
+    let mime = stored.mime.clone();
 
     state
         .current_video
         .write()
         .await
-        .replace(Resource::Http(stored_request.clone()));
+        .replace((resource_id.to_string(), stored));
 
     for (name, value) in incoming_request.headers().iter() {
         if name == http::header::HOST {
             continue;
         }
+        // The whole stream is needed to transcode it.
+        #[cfg(feature = "transcode")]
+        if transcode_target.is_some() && name == http::header::RANGE {
+            continue;
+        }
         stored_request
             .headers_mut()
             .insert(name.clone(), value.clone());
     }
 
     stored_request.headers_mut().remove_hop_by_hop_headers();
+    stored_request.apply_host_credentials(&state.host_credentials);
+
+    let inflight = state.inflight.register(&resource_id);
 
     let request = stored_request.into_reqwest_request(state.http_client.clone())?;
-    let response = state.http_client.execute(request).await?;
+    let response = tokio::select! {
+        response = state.http_client.execute(request) => response?,
+        _ = inflight.token().cancelled() => return Err(Error::Cancelled),
+    };
 
+    // An unsatisfiable range is the client's to handle, not a gateway failure.
     let status = response.status();
+    if status == StatusCode::RANGE_NOT_SATISFIABLE {
+        let mut headers = response.headers().clone();
+        headers.remove_hop_by_hop_headers();
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = status;
+        *response.headers_mut() = headers;
+        return Ok(response);
+    }
     if !status.is_success() {
         return Err(Error::RemoteServer(status));
     }
 
+    // Servers ignoring `Range` answer with a `200` and the full body, which is relayed as is.
     let mut headers = response.headers().clone();
     headers.remove_hop_by_hop_headers();
+    headers.fallback_content_type(mime.as_ref());
+    if status == StatusCode::PARTIAL_CONTENT {
+        headers
+            .entry(ACCEPT_RANGES)
+            .or_insert(HeaderValue::from_static("bytes"));
+    }
 
-    let stream = response.bytes_stream();
-    let body = Body::from_stream(stream);
+    let stream = response.bytes_stream().take_until(inflight.cancelled());
+    let mut body = Body::from_stream(stream);
+    if let Some(rate) = state.max_bytes_per_sec {
+        body = crate::throttle::throttle_body(body, rate);
+    }
+
+    #[cfg(feature = "transcode")]
+    if let Some(target) = transcode_target {
+        return crate::transcode::transcode(body, target, &state.ffmpeg_path);
+    }
 
     let mut response = Response::new(body);
     *response.status_mut() = status;
@@ -0,0 +1,47 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// How well a cache is performing, as counted since the proxy started.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Entries dropped before being used, e.g. because they expired.
+    pub evictions: u64,
+    pub current_size: usize,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct CacheCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl CacheCounters {
+    /// Counts a lookup as a hit or a miss depending on whether it found an entry.
+    pub(crate) fn record_lookup(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "imaging")]
+    pub(crate) fn record_evictions(&self, count: u64) {
+        self.evictions.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self, current_size: usize) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            current_size,
+        }
+    }
+}
+
+/// Metrics of the embedding application served at `/metrics`, e.g. extension call counts.
+/// Set with [`crate::MediaProxy::set_metrics_exporter`].
+pub trait MetricsExporter: Send + Sync {
+    /// Returns the metrics in the Prometheus text format.
+    fn export(&self) -> String;
+}
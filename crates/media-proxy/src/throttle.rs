@@ -0,0 +1,121 @@
+//! Bandwidth limiting of streamed bodies.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll, ready},
+    time::Duration,
+};
+
+use axum::body::Body;
+use futures_util::TryStreamExt;
+use tokio::{
+    io::{AsyncRead, ReadBuf},
+    time::{Instant, Sleep},
+};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+/// Fraction of a second worth of bytes a read waits for once the bucket is empty, so a
+/// throttled stream isn't read a few bytes at a time.
+const REFILL_FRACTION: u64 = 10;
+
+/// Caps the rate at which `body` is read to `bytes_per_sec`.
+pub(crate) fn throttle_body(body: Body, bytes_per_sec: u64) -> Body {
+    let reader = StreamReader::new(body.into_data_stream().map_err(io::Error::other));
+    Body::from_stream(ReaderStream::new(Throttled::new(reader, bytes_per_sec)))
+}
+
+/// A reader limited by a token bucket holding up to a second worth of bytes, so a stream may
+/// burst that much before being slowed down to the configured rate.
+pub(crate) struct Throttled<R> {
+    inner: R,
+    bytes_per_sec: u64,
+    available: u64,
+    refilled_at: Instant,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<R> Throttled<R> {
+    pub(crate) fn new(inner: R, bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1);
+        Self {
+            inner,
+            bytes_per_sec,
+            available: bytes_per_sec,
+            refilled_at: Instant::now(),
+            sleep: None,
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let earned = (now - self.refilled_at).as_secs_f64() * self.bytes_per_sec as f64;
+        // Fractions of a byte are kept for the next refill by not moving the clock forward.
+        if earned >= 1.0 {
+            self.available = (self.available + earned as u64).min(self.bytes_per_sec);
+            self.refilled_at = now;
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Throttled<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        loop {
+            if let Some(sleep) = &mut this.sleep {
+                ready!(sleep.as_mut().poll(cx));
+                this.sleep = None;
+            }
+
+            this.refill();
+            if this.available > 0 {
+                break;
+            }
+
+            let wanted = (buf.remaining() as u64)
+                .min(this.bytes_per_sec / REFILL_FRACTION)
+                .max(1);
+            let wait = Duration::from_secs_f64(wanted as f64 / this.bytes_per_sec as f64);
+            this.sleep = Some(Box::pin(tokio::time::sleep(wait)));
+        }
+
+        let len = buf.remaining().min(this.available as usize);
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(len));
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+        let read = limited.filled().len();
+        buf.advance(read);
+        this.available -= read as u64;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn capped_stream_takes_at_least_the_expected_time() {
+        const RATE: u64 = 16 * 1024;
+        let data = vec![0u8; 3 * RATE as usize];
+
+        let start = std::time::Instant::now();
+        let mut reader = Throttled::new(data.as_slice(), RATE);
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).await.unwrap();
+
+        assert_eq!(read.len(), data.len());
+        // The first second worth of bytes is a burst, the rest goes at the capped rate.
+        assert!(start.elapsed() >= Duration::from_millis(1900));
+    }
+}
@@ -0,0 +1,206 @@
+//! Locates files stored inside a zip archive so they can be streamed straight out of it.
+
+use std::{io::SeekFrom, ops::Range};
+
+use anyhow::{Result, bail};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+
+const LOCAL_HEADER_SIGNATURE: u32 = 0x04034b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x02014b50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06054b50;
+const END_OF_CENTRAL_DIRECTORY_LEN: u64 = 22;
+const MAX_COMMENT_LEN: u64 = u16::MAX as u64;
+
+/// Name of the archive entry requested through the `entry` query parameter of a stream URL.
+pub fn requested_entry(uri: &http::Uri) -> Option<String> {
+    url::form_urlencoded::parse(uri.query()?.as_bytes())
+        .find(|(key, _)| key == "entry")
+        .map(|(_, value)| value.into_owned())
+}
+
+/// Finds the byte range of the zip entry called `name` within `reader`.
+///
+/// Only entries stored without compression or encryption can be streamed, any other entry is
+/// rejected with an error.
+pub async fn find_stored_entry<R>(reader: &mut R, name: &str) -> Result<Range<u64>>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    let len = reader.seek(SeekFrom::End(0)).await?;
+    if len < END_OF_CENTRAL_DIRECTORY_LEN {
+        bail!("not a zip archive");
+    }
+
+    let tail_len = len.min(END_OF_CENTRAL_DIRECTORY_LEN + MAX_COMMENT_LEN);
+    reader.seek(SeekFrom::Start(len - tail_len)).await?;
+    let mut tail = vec![0; tail_len as usize];
+    reader.read_exact(&mut tail).await?;
+
+    let eocd = (0..=tail.len() - END_OF_CENTRAL_DIRECTORY_LEN as usize)
+        .rev()
+        .find(|&i| u32_at(&tail, i) == END_OF_CENTRAL_DIRECTORY_SIGNATURE)
+        .ok_or(anyhow::anyhow!("not a zip archive"))?;
+
+    let entries = u16_at(&tail, eocd + 10);
+    let directory_len = u32_at(&tail, eocd + 12);
+    let directory_offset = u32_at(&tail, eocd + 16);
+    if entries == u16::MAX || directory_offset == u32::MAX {
+        bail!("zip64 archives are not supported");
+    }
+
+    reader
+        .seek(SeekFrom::Start(directory_offset.into()))
+        .await?;
+    let mut directory = vec![0; directory_len as usize];
+    reader.read_exact(&mut directory).await?;
+
+    let mut pos = 0;
+    for _ in 0..entries {
+        if directory.len() < pos + 46 || u32_at(&directory, pos) != CENTRAL_HEADER_SIGNATURE {
+            bail!("malformed zip central directory");
+        }
+
+        let flags = u16_at(&directory, pos + 8);
+        let method = u16_at(&directory, pos + 10);
+        let compressed_len = u32_at(&directory, pos + 20);
+        let name_len = u16_at(&directory, pos + 28) as usize;
+        let extra_len = u16_at(&directory, pos + 30) as usize;
+        let comment_len = u16_at(&directory, pos + 32) as usize;
+        let header_offset = u32_at(&directory, pos + 42);
+
+        let entry_name = directory
+            .get(pos + 46..pos + 46 + name_len)
+            .ok_or(anyhow::anyhow!("malformed zip central directory"))?;
+        pos += 46 + name_len + extra_len + comment_len;
+
+        if entry_name != name.as_bytes() {
+            continue;
+        }
+        if flags & 1 != 0 {
+            bail!("zip entry {name} is encrypted");
+        }
+        if method != 0 {
+            bail!(
+                "zip entry {name} is compressed (method {method}), only stored entries can be streamed"
+            );
+        }
+
+        let mut local = [0; 30];
+        reader.seek(SeekFrom::Start(header_offset.into())).await?;
+        reader.read_exact(&mut local).await?;
+        if u32_at(&local, 0) != LOCAL_HEADER_SIGNATURE {
+            bail!("malformed zip local header");
+        }
+
+        let start = u64::from(header_offset)
+            + 30
+            + u64::from(u16_at(&local, 26))
+            + u64::from(u16_at(&local, 28));
+        return Ok(start..start + u64::from(compressed_len));
+    }
+
+    bail!("zip entry {name} not found")
+}
+
+fn u16_at(buf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([buf[at], buf[at + 1]])
+}
+
+fn u32_at(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// Builds a zip archive by hand, as no zip writer is available.
+    fn zip(entries: &[(&str, u16, &[u8])]) -> Vec<u8> {
+        let mut archive = Vec::new();
+        let mut directory = Vec::new();
+
+        for (name, method, data) in entries {
+            let offset = archive.len() as u32;
+            let len = data.len() as u32;
+
+            archive.extend(LOCAL_HEADER_SIGNATURE.to_le_bytes());
+            archive.extend([20, 0, 0, 0]);
+            archive.extend(method.to_le_bytes());
+            archive.extend([0; 8]);
+            archive.extend(len.to_le_bytes());
+            archive.extend(len.to_le_bytes());
+            archive.extend((name.len() as u16).to_le_bytes());
+            archive.extend(3u16.to_le_bytes());
+            archive.extend(name.as_bytes());
+            archive.extend([0; 3]);
+            archive.extend(*data);
+
+            directory.extend(CENTRAL_HEADER_SIGNATURE.to_le_bytes());
+            directory.extend([20, 0, 20, 0, 0, 0]);
+            directory.extend(method.to_le_bytes());
+            directory.extend([0; 8]);
+            directory.extend(len.to_le_bytes());
+            directory.extend(len.to_le_bytes());
+            directory.extend((name.len() as u16).to_le_bytes());
+            directory.extend([0; 12]);
+            directory.extend(offset.to_le_bytes());
+            directory.extend(name.as_bytes());
+        }
+
+        let directory_offset = archive.len() as u32;
+        let directory_len = directory.len() as u32;
+        archive.extend(directory);
+        archive.extend(END_OF_CENTRAL_DIRECTORY_SIGNATURE.to_le_bytes());
+        archive.extend([0; 4]);
+        archive.extend((entries.len() as u16).to_le_bytes());
+        archive.extend((entries.len() as u16).to_le_bytes());
+        archive.extend(directory_len.to_le_bytes());
+        archive.extend(directory_offset.to_le_bytes());
+        archive.extend([0; 2]);
+        archive
+    }
+
+    #[tokio::test]
+    async fn finds_stored_entry() {
+        let archive = zip(&[("a.txt", 0, b"first"), ("video.mkv", 0, b"matroska")]);
+
+        let range = find_stored_entry(&mut Cursor::new(&archive), "video.mkv")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            &archive[range.start as usize..range.end as usize],
+            b"matroska"
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_compressed_entry() {
+        let archive = zip(&[("video.mkv", 8, b"deflated")]);
+
+        let err = find_stored_entry(&mut Cursor::new(&archive), "video.mkv")
+            .await
+            .unwrap_err();
+
+        assert!(err.to_string().contains("compressed"));
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_entry() {
+        let archive = zip(&[("a.txt", 0, b"first")]);
+
+        assert!(
+            find_stored_entry(&mut Cursor::new(&archive), "video.mkv")
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn reads_entry_query_parameter() {
+        let uri: http::Uri = "/torrent/1/stream/0?entry=dir%2Fvideo.mkv".parse().unwrap();
+        assert_eq!(requested_entry(&uri).as_deref(), Some("dir/video.mkv"));
+    }
+}
@@ -3,48 +3,90 @@ use std::{path::PathBuf, sync::Arc};
 use anyhow::Result;
 use http::{Request, Response};
 
-use crate::torrent::{AddTorrentOptions, Torrent, TorrentBackend, TorrentFile, TorrentSource};
+use crate::torrent::{
+    AddTorrentOptions, BackendHealth, NotReady, Torrent, TorrentBackend, TorrentFile,
+    TorrentSource, TorrentStats,
+    resolved_cache::{ResolvedCache, ResolvedSource},
+    stream_cache::StreamCache,
+};
 
 pub struct RqbitTorrentBackend {
     api: librqbit::Api,
+    session: Arc<librqbit::Session>,
     client: reqwest::Client,
+    streams: StreamCache<librqbit::FileStream>,
+    resolved: ResolvedCache,
 }
 
 impl RqbitTorrentBackend {
     pub fn new(session: Arc<librqbit::Session>, client: reqwest::Client) -> Self {
         Self {
-            api: librqbit::Api::new(session, None),
+            api: librqbit::Api::new(session.clone(), None),
+            session,
             client,
+            streams: StreamCache::default(),
+            resolved: ResolvedCache::default(),
         }
     }
 
-    async fn resolve_torrent_source(
-        &self,
-        source: TorrentSource,
-    ) -> Result<librqbit::AddTorrent<'static>> {
-        match source {
-            TorrentSource::Http(mut request) => {
-                use crate::utils::{HopByHopHeadersExt, IntoReqwestRequest};
+    async fn fetch_torrent_file(&self, mut request: Box<crate::HttpRequest>) -> Result<Vec<u8>> {
+        use crate::utils::{HopByHopHeadersExt, IntoReqwestRequest};
 
-                request.headers_mut().remove_hop_by_hop_headers();
-                let req = request.into_reqwest_request(self.client.clone())?;
+        request.headers_mut().remove_hop_by_hop_headers();
+        let req = request.into_reqwest_request(self.client.clone())?;
 
-                let bytes = self.client.execute(req).await?.bytes().await?;
-                Ok(librqbit::AddTorrent::from_bytes(bytes.to_vec()))
-            }
-            TorrentSource::MagnetUri(uri) => Ok(librqbit::AddTorrent::from_url(uri)),
-        }
+        let response = self.client.execute(req).await?;
+        let gzipped = response
+            .headers()
+            .get(http::header::CONTENT_ENCODING)
+            .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"gzip"));
+        let bytes = response.bytes().await?;
+
+        decompress_torrent(&bytes, gzipped)
     }
 }
 
+/// librqbit only tells a torrent that is still starting apart from a failed one by its error
+/// message.
+fn stream_error(err: impl Into<anyhow::Error>) -> anyhow::Error {
+    let err = err.into();
+    let message = err.to_string();
+    if message.contains("initializing") || message.contains("metadata") {
+        return NotReady.into();
+    }
+    err
+}
+
+/// Some trackers serve gzipped `.torrent` files, either declared through `Content-Encoding` or
+/// not at all, so the gzip magic bytes are checked as well.
+fn decompress_torrent(bytes: &[u8], gzipped: bool) -> Result<Vec<u8>> {
+    use std::io::Read;
+
+    const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+    if !gzipped && !bytes.starts_with(&GZIP_MAGIC) {
+        return Ok(bytes.to_vec());
+    }
+
+    let mut decompressed = Vec::new();
+    flate2::read::GzDecoder::new(bytes).read_to_end(&mut decompressed)?;
+    Ok(decompressed)
+}
+
 #[async_trait::async_trait]
 impl TorrentBackend for RqbitTorrentBackend {
     async fn list_files(&self, source: &TorrentSource) -> Result<Vec<TorrentFile>> {
         use librqbit::{AddTorrent, AddTorrentOptions};
 
-        let uri = match source {
-            TorrentSource::Http(request) => &request.uri().to_string(),
-            TorrentSource::MagnetUri(uri) => uri,
+        let (add_torrent, torrent_file) = match source {
+            TorrentSource::Http(request) => {
+                let torrent_file = self.fetch_torrent_file(request.clone()).await?;
+                (
+                    AddTorrent::from_bytes(torrent_file.clone()),
+                    Some(torrent_file),
+                )
+            }
+            TorrentSource::MagnetUri(uri) => (AddTorrent::from_url(uri.clone()), None),
         };
 
         let options = AddTorrentOptions {
@@ -52,10 +94,15 @@ impl TorrentBackend for RqbitTorrentBackend {
             list_only: true,
             ..Default::default()
         };
-        let response = self
-            .api
-            .api_add_torrent(AddTorrent::from_url(uri), Some(options))
-            .await?;
+        let response = self.api.api_add_torrent(add_torrent, Some(options)).await?;
+
+        self.resolved.insert(
+            source,
+            ResolvedSource {
+                torrent_file,
+                peers: response.seen_peers.clone().unwrap_or_default(),
+            },
+        );
 
         let files = response
             .details
@@ -67,7 +114,13 @@ impl TorrentBackend for RqbitTorrentBackend {
                 let path = PathBuf::from(&f.name);
                 let name = path.file_name()?.to_string_lossy().to_string();
 
-                Some(TorrentFile { index, name, path })
+                Some(TorrentFile {
+                    index,
+                    name,
+                    path,
+                    size: f.length,
+                    downloaded: None,
+                })
             })
             .collect::<Vec<_>>();
 
@@ -83,20 +136,40 @@ impl TorrentBackend for RqbitTorrentBackend {
         source: TorrentSource,
         options: Option<AddTorrentOptions>,
     ) -> Result<Torrent> {
-        use librqbit::AddTorrentOptions;
+        use librqbit::{AddTorrent, AddTorrentOptions};
 
-        let add_torrent = self.resolve_torrent_source(source).await?;
+        // A torrent listed just before is neither fetched nor looked up again.
+        let resolved = self.resolved.take(&source).unwrap_or_default();
+        let add_torrent = match (resolved.torrent_file, source) {
+            (Some(torrent_file), _) => AddTorrent::from_bytes(torrent_file),
+            (None, TorrentSource::Http(request)) => {
+                AddTorrent::from_bytes(self.fetch_torrent_file(request).await?)
+            }
+            (None, TorrentSource::MagnetUri(uri)) => AddTorrent::from_url(uri),
+        };
+        let initial_peers = (!resolved.peers.is_empty()).then_some(resolved.peers);
 
-        let options = match options {
-            Some(options) => Some(AddTorrentOptions {
-                only_files: Some(options.file_indices),
+        let options = match (options, initial_peers) {
+            (None, None) => None,
+            (options, initial_peers) => Some(AddTorrentOptions {
+                only_files: options.map(|options| options.file_indices),
+                initial_peers,
                 overwrite: true,
                 ..Default::default()
             }),
-            None => None,
         };
 
         let added = self.api.api_add_torrent(add_torrent, options).await?;
+        let id = added
+            .id
+            .ok_or(anyhow::anyhow!("Torrent ID not available"))?;
+
+        // Data already on disk from an earlier session counts as downloaded.
+        let progress = self
+            .api
+            .api_stats_v1(librqbit::api::TorrentIdOrHash::Id(id))
+            .map(|stats| stats.file_progress)
+            .unwrap_or_default();
 
         let files = added
             .details
@@ -109,7 +182,13 @@ impl TorrentBackend for RqbitTorrentBackend {
                 let path = PathBuf::from(f.name);
                 let name = path.file_name()?.to_string_lossy().to_string();
 
-                Some(TorrentFile { index, name, path })
+                Some(TorrentFile {
+                    index,
+                    name,
+                    path,
+                    size: f.length,
+                    downloaded: Some(progress.get(index).copied().unwrap_or(0)),
+                })
             })
             .collect::<Vec<_>>();
 
@@ -118,10 +197,7 @@ impl TorrentBackend for RqbitTorrentBackend {
         }
 
         Ok(Torrent {
-            id: added
-                .id
-                .ok_or(anyhow::anyhow!("Torrent ID not available"))?
-                .to_string(),
+            id: id.to_string(),
             name: added.details.name,
             files,
         })
@@ -140,19 +216,6 @@ impl TorrentBackend for RqbitTorrentBackend {
 
         let headers = request.headers();
 
-        let torrent_id = TorrentIdOrHash::Id(torrent_id.parse()?);
-
-        let mut stream = self.api.api_stream(torrent_id, file_index)?;
-
-        let total_len = stream.len();
-        let mut status = StatusCode::OK;
-        let mut response_headers = HeaderMap::new();
-
-        response_headers.insert(
-            http::header::ACCEPT_RANGES,
-            http::HeaderValue::from_static("bytes"),
-        );
-
         let range = headers
             .get(http::header::RANGE)
             .and_then(|v| v.to_str().ok())
@@ -164,12 +227,55 @@ impl TorrentBackend for RqbitTorrentBackend {
                 Some((start, end))
             });
 
+        let start = range.map_or(0, |(start, _)| start);
+
+        // Entries of an archive are served as a window into the archive file.
+        #[cfg(feature = "archive")]
+        let entry = match super::archive::requested_entry(request.uri()) {
+            Some(name) => {
+                let id = TorrentIdOrHash::Id(torrent_id.parse()?);
+                let mut stream = self.api.api_stream(id, file_index).map_err(stream_error)?;
+                Some(super::archive::find_stored_entry(&mut stream, &name).await?)
+            }
+            None => None,
+        };
+        #[cfg(not(feature = "archive"))]
+        let entry: Option<std::ops::Range<u64>> = None;
+
+        let offset = entry.as_ref().map_or(0, |entry| entry.start) + start;
+
+        // Players tend to issue many small sequential ranges, reuse the stream left at the
+        // end of the previous one instead of opening and seeking a new one each time.
+        let stream = match self.streams.take(torrent_id, file_index, offset) {
+            Some(stream) => stream,
+            None => {
+                let id = TorrentIdOrHash::Id(torrent_id.parse()?);
+                let mut stream = self.api.api_stream(id, file_index).map_err(stream_error)?;
+                if offset > 0 {
+                    stream
+                        .seek(SeekFrom::Start(offset))
+                        .await
+                        .map_err(stream_error)?;
+                }
+                stream
+            }
+        };
+
+        let total_len = entry.map_or(stream.len(), |entry| entry.end - entry.start);
+        let stream = self.streams.track(torrent_id, file_index, stream, offset);
+
+        let mut status = StatusCode::OK;
+        let mut response_headers = HeaderMap::new();
+
+        response_headers.insert(
+            http::header::ACCEPT_RANGES,
+            http::HeaderValue::from_static("bytes"),
+        );
+
         let reader: Box<dyn tokio::io::AsyncRead + Send + Unpin> = if let Some((start, end)) = range
         {
             status = StatusCode::PARTIAL_CONTENT;
 
-            stream.seek(SeekFrom::Start(start)).await?;
-
             let end = end.unwrap_or(total_len);
             let len = end - start;
 
@@ -192,7 +298,7 @@ impl TorrentBackend for RqbitTorrentBackend {
                 total_len.to_string().parse().unwrap(),
             );
 
-            Box::new(stream)
+            Box::new(stream.take(total_len))
         };
 
         let body = axum::body::Body::from_stream(tokio_util::io::ReaderStream::with_capacity(
@@ -214,7 +320,61 @@ impl TorrentBackend for RqbitTorrentBackend {
 
         let idx = TorrentIdOrHash::Id(torrent.parse()?);
         self.api.api_torrent_action_delete(idx).await?;
+        self.streams.remove_torrent(torrent);
 
         Ok(())
     }
+
+    async fn health(&self) -> Result<BackendHealth> {
+        let dht_nodes = self
+            .api
+            .api_dht_stats()
+            .map(|stats| stats.routing_table_size)
+            .unwrap_or(0);
+
+        Ok(BackendHealth {
+            listening: self.session.tcp_listen_port().is_some(),
+            dht_nodes,
+            active_torrents: self.api.api_torrent_list().torrents.len(),
+        })
+    }
+
+    /// Pauses the torrents and stops the session, which persists them when the session was
+    /// created with persistence enabled.
+    async fn shutdown(&self) -> Result<()> {
+        use librqbit::api::TorrentIdOrHash;
+
+        for torrent in self.api.api_torrent_list().torrents {
+            // Paused torrents fail to pause again, there is nothing to do for them.
+            self.api
+                .api_torrent_action_pause(TorrentIdOrHash::Id(torrent.id))
+                .await
+                .ok();
+        }
+        self.streams.clear();
+        self.session.stop().await;
+
+        Ok(())
+    }
+
+    async fn download_stats(&self, torrent_id: &str) -> Result<TorrentStats> {
+        use librqbit::api::TorrentIdOrHash;
+
+        let stats = self
+            .api
+            .api_stats_v1(TorrentIdOrHash::Id(torrent_id.parse()?))?;
+
+        // Live stats are only kept while the torrent is running.
+        let (download_speed, peers) = stats.live.as_ref().map_or((0, 0), |live| {
+            let bytes_per_sec = live.download_speed.mbps * 1024.0 * 1024.0;
+            (bytes_per_sec as u64, live.snapshot.peer_stats.live)
+        });
+
+        Ok(TorrentStats {
+            downloaded_bytes: stats.progress_bytes,
+            total_bytes: stats.total_bytes,
+            download_speed,
+            peers,
+        })
+    }
 }
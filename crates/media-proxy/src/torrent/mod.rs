@@ -1,10 +1,22 @@
+#[cfg(feature = "archive")]
+pub mod archive;
 #[cfg(feature = "torrent-librqbit")]
 pub mod librqbit;
+#[cfg(feature = "torrent-librqbit")]
+mod resolved_cache;
+#[cfg(feature = "torrent-librqbit")]
+mod stream_cache;
+#[cfg(feature = "torrent-transmission")]
+pub mod transmission;
 
-use std::path::PathBuf;
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use anyhow::Result;
 use http::{Request, Response};
+use serde::Serialize;
 
 use crate::HttpRequest;
 
@@ -26,11 +38,83 @@ pub struct Torrent {
     pub files: Vec<TorrentFile>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct TorrentFile {
     pub index: usize,
     pub name: String,
     pub path: PathBuf,
+    /// Size of the file in bytes, from the torrent metadata.
+    pub size: u64,
+    /// Bytes of the file downloaded so far. `None` when the torrent is only listed, not added.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub downloaded: Option<u64>,
+}
+
+/// Finds the file at `path` in a torrent, or the only one named `path`.
+pub(crate) fn find_file<'a>(files: &'a [TorrentFile], path: &str) -> Option<&'a TorrentFile> {
+    if let Some(file) = files.iter().find(|file| file.path == Path::new(path)) {
+        return Some(file);
+    }
+
+    let mut named = files.iter().filter(|file| file.name == path);
+    match (named.next(), named.next()) {
+        (Some(file), None) => Some(file),
+        _ => None,
+    }
+}
+
+/// Reads a resolution tag like `1080p` or `4K` off a release file name, normalized to the
+/// `{height}p` form.
+pub(crate) fn resolution_hint(name: &str) -> Option<String> {
+    name.split(|c: char| !c.is_ascii_alphanumeric())
+        .find_map(|token| {
+            if token.eq_ignore_ascii_case("4k") {
+                return Some(2160);
+            }
+            let height = token.strip_suffix(['p', 'P'])?.parse::<u16>().ok()?;
+            (240..=4320).contains(&height).then_some(height)
+        })
+        .map(|height| format!("{height}p"))
+}
+
+/// What a stream request does when it seeks into data that isn't downloaded yet.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum UnavailableDataPolicy {
+    /// Wait for the data to arrive, however long it takes.
+    #[default]
+    Block,
+    /// Fail with a `503` and a `Retry-After` hint when no data arrives within `grace`, so the
+    /// player retries instead of hanging.
+    FailFast { grace: Duration },
+}
+
+/// Returned by a backend when a stream can't be served yet, e.g. while the torrent metadata or
+/// the requested data is still being downloaded. Stream requests retry until the torrent is
+/// ready or they time out.
+#[derive(Debug, thiserror::Error)]
+#[error("Torrent is not ready yet")]
+pub struct NotReady;
+
+/// Whether a backend is actually functional, as opposed to merely configured.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackendHealth {
+    pub listening: bool,
+    pub dht_nodes: usize,
+    pub active_torrents: usize,
+}
+
+/// Download progress of an added torrent, across the files selected when it was added.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TorrentStats {
+    pub downloaded_bytes: u64,
+    pub total_bytes: u64,
+    /// Current download speed in bytes per second.
+    pub download_speed: u64,
+    /// Peers currently connected.
+    pub peers: usize,
 }
 
 #[async_trait::async_trait]
@@ -50,10 +134,85 @@ pub trait TorrentBackend: Send + Sync {
         request: Request<axum::body::Body>,
     ) -> Result<Response<axum::body::Body>>;
 
+    /// Raises the priority of the pieces of a file from `byte_offset` on, called before a
+    /// stream request seeks there so the player doesn't wait on the sequential download.
+    ///
+    /// Does nothing by default, for backends whose streams already fetch pieces from their
+    /// read position first, like librqbit's.
+    async fn prioritize(
+        &self,
+        torrent_id: &str,
+        file_index: usize,
+        byte_offset: u64,
+    ) -> Result<()> {
+        let _ = (torrent_id, file_index, byte_offset);
+        Ok(())
+    }
+
     async fn cancel_torrent(&self, torrent: &str) -> Result<()>;
+
+    async fn health(&self) -> Result<BackendHealth>;
+
+    /// Pauses every torrent the backend runs, keeping their downloaded data on disk so they
+    /// resume from there when added again. The backend isn't meant to be used afterwards.
+    async fn shutdown(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn download_stats(&self, torrent_id: &str) -> Result<TorrentStats>;
 }
 
 #[async_trait::async_trait]
 pub trait TorrentFileSelector: Send + Sync {
     async fn select(&self, files: &[TorrentFile]) -> Result<Vec<usize>>;
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{TorrentFile, find_file, resolution_hint};
+
+    fn file(index: usize, path: &str) -> TorrentFile {
+        let path = PathBuf::from(path);
+        TorrentFile {
+            index,
+            name: path.file_name().unwrap().to_string_lossy().to_string(),
+            path,
+            size: 0,
+            downloaded: None,
+        }
+    }
+
+    #[test]
+    fn finds_files_by_path_or_unique_name() {
+        let files = [
+            file(0, "Show/S01/01.mkv"),
+            file(1, "Show/S02/01.mkv"),
+            file(2, "Show/S02/02.mkv"),
+        ];
+
+        assert_eq!(find_file(&files, "Show/S02/01.mkv").unwrap().index, 1);
+        assert_eq!(find_file(&files, "02.mkv").unwrap().index, 2);
+        assert!(find_file(&files, "01.mkv").is_none());
+        assert!(find_file(&files, "03.mkv").is_none());
+    }
+
+    #[test]
+    fn reads_resolution_tags() {
+        assert_eq!(
+            resolution_hint("[Group] Show - 01 (1080p).mkv").as_deref(),
+            Some("1080p")
+        );
+        assert_eq!(
+            resolution_hint("Show.S01E01.720P.WEB.mkv").as_deref(),
+            Some("720p")
+        );
+        assert_eq!(
+            resolution_hint("Show 01 4K HDR.mkv").as_deref(),
+            Some("2160p")
+        );
+        assert_eq!(resolution_hint("Show 01.mkv"), None);
+        assert_eq!(resolution_hint("Show 2p.mkv"), None);
+    }
+}
@@ -0,0 +1,87 @@
+use std::{collections::VecDeque, net::SocketAddr, sync::Mutex};
+
+use crate::torrent::TorrentSource;
+
+/// Sources listed but never added are dropped past this many, oldest first.
+const MAX_ENTRIES: usize = 16;
+
+/// What was learned about a torrent source while listing its files.
+#[derive(Debug, Clone, Default)]
+pub struct ResolvedSource {
+    /// The `.torrent` file, for sources fetched over HTTP.
+    pub torrent_file: Option<Vec<u8>>,
+    /// Peers that served the metadata, sparing a new DHT lookup.
+    pub peers: Vec<SocketAddr>,
+}
+
+/// Keeps resolved sources between listing a torrent's files and adding it, so the torrent
+/// isn't fetched or looked up a second time.
+#[derive(Default)]
+pub struct ResolvedCache {
+    entries: Mutex<VecDeque<(String, ResolvedSource)>>,
+}
+
+impl ResolvedCache {
+    pub fn insert(&self, source: &TorrentSource, resolved: ResolvedSource) {
+        let key = source_key(source);
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|(k, _)| *k != key);
+        if entries.len() >= MAX_ENTRIES {
+            entries.pop_front();
+        }
+        entries.push_back((key, resolved));
+    }
+
+    /// Takes what was resolved for `source`, only the first add after a listing benefits.
+    pub fn take(&self, source: &TorrentSource) -> Option<ResolvedSource> {
+        let key = source_key(source);
+        let mut entries = self.entries.lock().unwrap();
+        let position = entries.iter().position(|(k, _)| *k == key)?;
+        entries.remove(position).map(|(_, resolved)| resolved)
+    }
+}
+
+fn source_key(source: &TorrentSource) -> String {
+    match source {
+        TorrentSource::Http(request) => request.uri().to_string(),
+        TorrentSource::MagnetUri(uri) => uri.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MAX_ENTRIES, ResolvedCache, ResolvedSource};
+    use crate::torrent::TorrentSource;
+
+    fn magnet(n: usize) -> TorrentSource {
+        TorrentSource::MagnetUri(format!("magnet:?xt=urn:btih:{n:040}"))
+    }
+
+    #[test]
+    fn listed_source_is_resolved_once() {
+        let cache = ResolvedCache::default();
+        let resolved = ResolvedSource {
+            torrent_file: Some(b"d4:infod".to_vec()),
+            peers: vec!["127.0.0.1:6881".parse().unwrap()],
+        };
+        cache.insert(&magnet(1), resolved);
+
+        let taken = cache.take(&magnet(1)).expect("listed source was not kept");
+        assert_eq!(taken.torrent_file.as_deref(), Some(&b"d4:infod"[..]));
+        assert_eq!(taken.peers.len(), 1);
+        assert!(cache.take(&magnet(1)).is_none());
+        assert!(cache.take(&magnet(2)).is_none());
+    }
+
+    #[test]
+    fn oldest_sources_are_dropped() {
+        let cache = ResolvedCache::default();
+        for n in 0..=MAX_ENTRIES {
+            cache.insert(&magnet(n), ResolvedSource::default());
+        }
+
+        assert!(cache.take(&magnet(0)).is_none());
+        assert!(cache.take(&magnet(1)).is_some());
+        assert!(cache.take(&magnet(MAX_ENTRIES)).is_some());
+    }
+}
@@ -0,0 +1,175 @@
+use std::{
+    collections::HashMap,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, ReadBuf};
+
+type StreamKey = (String, usize);
+
+/// Keeps file streams left positioned at the end of a served range, so a request continuing
+/// sequentially from there can reuse the stream instead of opening and seeking a new one.
+pub struct StreamCache<S> {
+    streams: Arc<Mutex<HashMap<StreamKey, (S, u64)>>>,
+}
+
+impl<S> Default for StreamCache<S> {
+    fn default() -> Self {
+        Self {
+            streams: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl<S> StreamCache<S> {
+    /// Takes the cached stream of a torrent file if it is positioned exactly at `offset`.
+    pub fn take(&self, torrent_id: &str, file_index: usize, offset: u64) -> Option<S> {
+        let mut streams = self.streams.lock().unwrap();
+        let key = (torrent_id.to_owned(), file_index);
+        match streams.get(&key) {
+            Some((_, position)) if *position == offset => {
+                streams.remove(&key).map(|(stream, _)| stream)
+            }
+            _ => None,
+        }
+    }
+
+    /// Wraps a stream positioned at `offset` so it returns to the cache when dropped.
+    pub fn track(
+        &self,
+        torrent_id: &str,
+        file_index: usize,
+        stream: S,
+        offset: u64,
+    ) -> TrackedStream<S> {
+        TrackedStream {
+            key: (torrent_id.to_owned(), file_index),
+            stream: Some(stream),
+            position: offset,
+            streams: Arc::clone(&self.streams),
+        }
+    }
+
+    /// Drops every cached stream.
+    pub fn clear(&self) {
+        self.streams.lock().unwrap().clear();
+    }
+
+    /// Drops every cached stream of a torrent.
+    pub fn remove_torrent(&self, torrent_id: &str) {
+        self.streams
+            .lock()
+            .unwrap()
+            .retain(|(id, _), _| id != torrent_id);
+    }
+}
+
+pub struct TrackedStream<S> {
+    key: StreamKey,
+    stream: Option<S>,
+    position: u64,
+    streams: Arc<Mutex<HashMap<StreamKey, (S, u64)>>>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TrackedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let Some(stream) = this.stream.as_mut() else {
+            return Poll::Ready(Ok(()));
+        };
+
+        let before = buf.filled().len();
+        let result = Pin::new(stream).poll_read(cx, buf);
+        match &result {
+            Poll::Ready(Ok(())) => this.position += (buf.filled().len() - before) as u64,
+            // The position is unknown after a failed read, don't hand the stream out again.
+            Poll::Ready(Err(_)) => this.stream = None,
+            Poll::Pending => {}
+        }
+
+        result
+    }
+}
+
+impl<S> Drop for TrackedStream<S> {
+    fn drop(&mut self) {
+        let Some(stream) = self.stream.take() else {
+            return;
+        };
+
+        if let Ok(mut streams) = self.streams.lock() {
+            streams.insert(self.key.clone(), (stream, self.position));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use tokio::io::AsyncReadExt;
+
+    use super::StreamCache;
+
+    #[tokio::test]
+    async fn sequential_range_reuses_stream() {
+        let cache = StreamCache::default();
+        let stream = Cursor::new(b"0123456789".to_vec());
+
+        let mut first = Vec::new();
+        cache
+            .track("1", 0, stream, 0)
+            .take(4)
+            .read_to_end(&mut first)
+            .await
+            .unwrap();
+        assert_eq!(first, b"0123");
+
+        let stream = cache.take("1", 0, 4).expect("stream was not reused");
+        let mut second = Vec::new();
+        cache
+            .track("1", 0, stream, 4)
+            .take(3)
+            .read_to_end(&mut second)
+            .await
+            .unwrap();
+        assert_eq!(second, b"456");
+    }
+
+    #[tokio::test]
+    async fn non_sequential_range_needs_fresh_stream() {
+        let cache = StreamCache::default();
+        let stream = Cursor::new(b"0123456789".to_vec());
+
+        let mut buf = Vec::new();
+        cache
+            .track("1", 0, stream, 0)
+            .take(4)
+            .read_to_end(&mut buf)
+            .await
+            .unwrap();
+
+        assert!(cache.take("1", 0, 8).is_none());
+        assert!(cache.take("1", 1, 4).is_none());
+        assert!(cache.take("1", 0, 4).is_some());
+    }
+
+    #[test]
+    fn remove_torrent_drops_its_streams() {
+        let cache = StreamCache::default();
+        drop(cache.track("1", 0, Cursor::new(Vec::<u8>::new()), 0));
+        drop(cache.track("2", 0, Cursor::new(Vec::<u8>::new()), 0));
+
+        cache.remove_torrent("1");
+
+        assert!(cache.take("1", 0, 0).is_none());
+        assert!(cache.take("2", 0, 0).is_some());
+    }
+}
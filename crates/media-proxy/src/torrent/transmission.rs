@@ -0,0 +1,513 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::{Result, bail};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use http::{HeaderValue, Request, Response, StatusCode};
+use serde::{Deserialize, de::DeserializeOwned};
+use serde_json::{Value, json};
+use url::Url;
+
+use crate::torrent::{
+    AddTorrentOptions, BackendHealth, NotReady, Torrent, TorrentBackend, TorrentFile,
+    TorrentSource, TorrentStats,
+};
+
+const SESSION_ID_HEADER: &str = "x-transmission-session-id";
+
+/// How often the daemon is polled while it fetches the metadata of a magnet URI.
+const METADATA_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+pub const DEFAULT_METADATA_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Streams torrents downloaded by a Transmission daemon, driven over its RPC interface.
+///
+/// Transmission doesn't serve file contents, so they are read from its download directory,
+/// which must be reachable from this process. Only the pieces downloaded so far are served, a
+/// range reaching past them is cut short and a range starting in a missing piece waits for
+/// it like a torrent that isn't ready yet.
+pub struct TransmissionTorrentBackend {
+    rpc_url: Url,
+    client: reqwest::Client,
+    credentials: Option<(String, String)>,
+    download_dir: Option<PathBuf>,
+    metadata_timeout: Duration,
+    session_id: Mutex<Option<HeaderValue>>,
+    /// Torrents added through this backend, the only ones it stops on shutdown.
+    added: Mutex<HashSet<i64>>,
+}
+
+impl TransmissionTorrentBackend {
+    /// `rpc_url` is the daemon's RPC endpoint, usually `http://host:9091/transmission/rpc`.
+    pub fn new(rpc_url: Url, client: reqwest::Client) -> Self {
+        Self {
+            rpc_url,
+            client,
+            credentials: None,
+            download_dir: None,
+            metadata_timeout: DEFAULT_METADATA_TIMEOUT,
+            session_id: Mutex::new(None),
+            added: Mutex::default(),
+        }
+    }
+
+    pub fn with_credentials(mut self, username: String, password: String) -> Self {
+        self.credentials = Some((username, password));
+        self
+    }
+
+    /// Reads files from `dir` instead of the download directory reported by the daemon, e.g.
+    /// when it runs in a container with the directory mounted elsewhere.
+    pub fn with_download_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.download_dir = Some(dir.into());
+        self
+    }
+
+    /// How long to wait for the metadata of a magnet URI. Defaults to
+    /// [`DEFAULT_METADATA_TIMEOUT`].
+    pub fn with_metadata_timeout(mut self, timeout: Duration) -> Self {
+        self.metadata_timeout = timeout;
+        self
+    }
+
+    async fn rpc<T: DeserializeOwned>(&self, method: &str, arguments: Value) -> Result<T> {
+        let body = serde_json::to_vec(&json!({ "method": method, "arguments": arguments }))?;
+
+        // The daemon rejects the first request of a session with a `409` carrying the id to
+        // send from then on.
+        for _ in 0..2 {
+            let mut request = self.client.post(self.rpc_url.clone()).body(body.clone());
+            if let Some(session_id) = self.session_id.lock().unwrap().clone() {
+                request = request.header(SESSION_ID_HEADER, session_id);
+            }
+            if let Some((username, password)) = &self.credentials {
+                request = request.basic_auth(username, Some(password));
+            }
+
+            let response = request.send().await?;
+            if response.status() == StatusCode::CONFLICT {
+                *self.session_id.lock().unwrap() =
+                    response.headers().get(SESSION_ID_HEADER).cloned();
+                continue;
+            }
+
+            let response: RpcResponse<T> =
+                serde_json::from_slice(&response.error_for_status()?.bytes().await?)?;
+            if response.result != "success" {
+                bail!("Transmission {method} failed: {}", response.result);
+            }
+            return Ok(response.arguments);
+        }
+
+        bail!("Transmission kept rejecting the session id")
+    }
+
+    async fn add(&self, source: TorrentSource, paused: bool) -> Result<AddedTorrent> {
+        let mut arguments = json!({ "paused": paused });
+        match source {
+            TorrentSource::Http(mut request) => {
+                use crate::utils::{HopByHopHeadersExt, IntoReqwestRequest};
+
+                request.headers_mut().remove_hop_by_hop_headers();
+                let request = request.into_reqwest_request(self.client.clone())?;
+                let bytes = self
+                    .client
+                    .execute(request)
+                    .await?
+                    .error_for_status()?
+                    .bytes()
+                    .await?;
+                arguments["metainfo"] = BASE64_STANDARD.encode(bytes).into();
+            }
+            TorrentSource::MagnetUri(uri) => arguments["filename"] = uri.into(),
+        }
+
+        let added: AddTorrentResponse = self.rpc("torrent-add", arguments).await?;
+        match (added.added, added.duplicate) {
+            (Some(torrent), _) => Ok(AddedTorrent {
+                id: torrent.id,
+                is_new: true,
+            }),
+            (None, Some(torrent)) => Ok(AddedTorrent {
+                id: torrent.id,
+                is_new: false,
+            }),
+            (None, None) => bail!("Transmission didn't report the added torrent"),
+        }
+    }
+
+    async fn details(&self, id: i64) -> Result<TorrentDetails> {
+        let fields = [
+            "id",
+            "name",
+            "downloadDir",
+            "files",
+            "wanted",
+            "pieces",
+            "pieceSize",
+            "haveValid",
+            "sizeWhenDone",
+            "rateDownload",
+            "peersConnected",
+        ];
+        let response: TorrentGetResponse = self
+            .rpc("torrent-get", json!({ "ids": [id], "fields": fields }))
+            .await?;
+
+        response
+            .torrents
+            .into_iter()
+            .next()
+            .ok_or(anyhow::anyhow!("Torrent {id} not found"))
+    }
+
+    /// Polls the torrent until its metadata, and with it its file list, is known.
+    async fn details_with_files(&self, id: i64) -> Result<TorrentDetails> {
+        let deadline = tokio::time::Instant::now() + self.metadata_timeout;
+        loop {
+            let details = self.details(id).await?;
+            if !details.files.is_empty() {
+                return Ok(details);
+            }
+            if tokio::time::Instant::now() >= deadline {
+                bail!("Timed out waiting for the torrent metadata");
+            }
+            tokio::time::sleep(METADATA_POLL_INTERVAL).await;
+        }
+    }
+
+    async fn remove(&self, id: i64, delete_local_data: bool) -> Result<()> {
+        let arguments = json!({ "ids": [id], "delete-local-data": delete_local_data });
+        self.rpc::<serde::de::IgnoredAny>("torrent-remove", arguments)
+            .await?;
+        Ok(())
+    }
+
+    fn file_path(&self, details: &TorrentDetails, file: &FileDetails) -> PathBuf {
+        let dir = self
+            .download_dir
+            .as_deref()
+            .unwrap_or(Path::new(&details.download_dir));
+        dir.join(&file.name)
+    }
+}
+
+#[async_trait::async_trait]
+impl TorrentBackend for TransmissionTorrentBackend {
+    async fn list_files(&self, source: &TorrentSource) -> Result<Vec<TorrentFile>> {
+        // There is no way to only list a torrent, add it paused and drop it again unless it
+        // was already there.
+        let added = self.add(source.clone(), true).await?;
+        let details = self.details_with_files(added.id).await;
+        if added.is_new {
+            self.remove(added.id, true).await.ok();
+        }
+
+        let files = details?
+            .files
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, file)| file.into_torrent_file(index, None))
+            .collect::<Vec<_>>();
+
+        if files.is_empty() {
+            bail!("No valid files found in torrent")
+        }
+
+        Ok(files)
+    }
+
+    async fn add_torrent(
+        &self,
+        source: TorrentSource,
+        options: Option<AddTorrentOptions>,
+    ) -> Result<Torrent> {
+        let added = self.add(source, false).await?;
+        self.added.lock().unwrap().insert(added.id);
+        let mut details = self.details_with_files(added.id).await?;
+
+        if let Some(options) = options {
+            let unwanted = (0..details.files.len())
+                .filter(|index| !options.file_indices.contains(index))
+                .collect::<Vec<_>>();
+            let arguments = json!({
+                "ids": [added.id],
+                "files-wanted": options.file_indices,
+                "files-unwanted": unwanted,
+            });
+            self.rpc::<serde::de::IgnoredAny>("torrent-set", arguments)
+                .await?;
+            details = self.details(added.id).await?;
+        }
+
+        let files = details
+            .files
+            .into_iter()
+            .enumerate()
+            .filter(|(index, _)| details.wanted.get(*index).is_none_or(is_wanted))
+            .filter_map(|(index, file)| {
+                let downloaded = file.bytes_completed;
+                file.into_torrent_file(index, Some(downloaded))
+            })
+            .collect::<Vec<_>>();
+
+        if files.is_empty() {
+            bail!("No valid files were included in torrent");
+        }
+
+        Ok(Torrent {
+            id: details.id.to_string(),
+            name: Some(details.name),
+            files,
+        })
+    }
+
+    async fn handle_stream_request(
+        &self,
+        torrent_id: &str,
+        file_index: usize,
+        request: Request<axum::body::Body>,
+    ) -> Result<Response<axum::body::Body>> {
+        use std::io::SeekFrom;
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+        let details = self.details(torrent_id.parse()?).await?;
+        let file = details
+            .files
+            .get(file_index)
+            .ok_or(anyhow::anyhow!("Torrent has no file {file_index}"))?;
+        let total_len = file.length;
+
+        let requested = request
+            .headers()
+            .get(http::header::RANGE)
+            .and_then(|v| v.to_str().ok());
+        let range = match requested {
+            Some(value) => match crate::utils::parse_byte_range(value, total_len) {
+                Some(range) => Some(range),
+                None => {
+                    return Ok(Response::builder()
+                        .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                        .header(http::header::CONTENT_RANGE, format!("bytes */{total_len}"))
+                        .body(axum::body::Body::empty())
+                        .unwrap());
+                }
+            },
+            None => None,
+        };
+        let start = range.as_ref().map_or(0, |range| range.start);
+        let end = range.as_ref().map_or(total_len, |range| range.end);
+
+        let pieces = BASE64_STANDARD.decode(&details.pieces)?;
+        let file_offset = details.files[..file_index]
+            .iter()
+            .map(|file| file.length)
+            .sum::<u64>();
+        let available = available_len(
+            &pieces,
+            details.piece_size,
+            file_offset + start,
+            file_offset + end,
+        );
+        if available == 0 {
+            return Err(NotReady.into());
+        }
+        let end = start + available;
+
+        // Partially downloaded files may still carry Transmission's `.part` suffix.
+        let path = self.file_path(&details, file);
+        let mut partial = path.clone().into_os_string();
+        partial.push(".part");
+        let mut stream = match tokio::fs::File::open(&path).await {
+            Ok(stream) => stream,
+            Err(_) => tokio::fs::File::open(&partial).await?,
+        };
+        if start > 0 {
+            stream.seek(SeekFrom::Start(start)).await?;
+        }
+
+        let mut builder = Response::builder()
+            .header(http::header::ACCEPT_RANGES, "bytes")
+            .header(http::header::CONTENT_LENGTH, end - start);
+        // A range cut short by missing pieces is still answered with what is there.
+        if range.is_some() || end < total_len {
+            builder = builder.status(StatusCode::PARTIAL_CONTENT).header(
+                http::header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end - 1, total_len),
+            );
+        }
+
+        let body = axum::body::Body::from_stream(tokio_util::io::ReaderStream::with_capacity(
+            stream.take(end - start),
+            64 * 1024,
+        ));
+
+        Ok(builder.body(body).unwrap())
+    }
+
+    async fn cancel_torrent(&self, torrent: &str) -> Result<()> {
+        let id = torrent.parse()?;
+        self.added.lock().unwrap().remove(&id);
+        self.remove(id, true).await
+    }
+
+    /// Stops the torrents added through this backend, the daemon keeps them along with their
+    /// data.
+    async fn shutdown(&self) -> Result<()> {
+        let ids = self.added.lock().unwrap().drain().collect::<Vec<_>>();
+        if !ids.is_empty() {
+            self.rpc::<serde::de::IgnoredAny>("torrent-stop", json!({ "ids": ids }))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Transmission doesn't report the size of its DHT routing table, `dht_nodes` is always 0.
+    async fn health(&self) -> Result<BackendHealth> {
+        let session: SessionStats = self.rpc("session-stats", json!({})).await?;
+
+        Ok(BackendHealth {
+            listening: true,
+            dht_nodes: 0,
+            active_torrents: session.active_torrent_count,
+        })
+    }
+
+    async fn download_stats(&self, torrent_id: &str) -> Result<TorrentStats> {
+        let details = self.details(torrent_id.parse()?).await?;
+
+        Ok(TorrentStats {
+            downloaded_bytes: details.have_valid,
+            total_bytes: details.size_when_done,
+            download_speed: details.rate_download,
+            peers: details.peers_connected,
+        })
+    }
+}
+
+/// Number of bytes of the torrent from `start` up to `end` covered by consecutive downloaded
+/// pieces. `pieces` is a bitfield, the highest bit of the first byte being the first piece.
+fn available_len(pieces: &[u8], piece_size: u64, start: u64, end: u64) -> u64 {
+    if piece_size == 0 || start >= end {
+        return 0;
+    }
+
+    let has_piece = |piece: u64| {
+        pieces
+            .get((piece / 8) as usize)
+            .is_some_and(|byte| byte & (0x80 >> (piece % 8)) != 0)
+    };
+
+    let mut piece = start / piece_size;
+    while piece * piece_size < end && has_piece(piece) {
+        piece += 1;
+    }
+
+    (piece * piece_size).min(end).saturating_sub(start)
+}
+
+fn is_wanted(wanted: &Value) -> bool {
+    wanted
+        .as_bool()
+        .or_else(|| wanted.as_u64().map(|wanted| wanted != 0))
+        .unwrap_or(true)
+}
+
+struct AddedTorrent {
+    id: i64,
+    is_new: bool,
+}
+
+#[derive(Deserialize)]
+struct RpcResponse<T> {
+    result: String,
+    arguments: T,
+}
+
+#[derive(Deserialize)]
+struct AddTorrentResponse {
+    #[serde(rename = "torrent-added")]
+    added: Option<TorrentRef>,
+    #[serde(rename = "torrent-duplicate")]
+    duplicate: Option<TorrentRef>,
+}
+
+#[derive(Deserialize)]
+struct TorrentRef {
+    id: i64,
+}
+
+#[derive(Deserialize)]
+struct TorrentGetResponse {
+    torrents: Vec<TorrentDetails>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TorrentDetails {
+    id: i64,
+    name: String,
+    download_dir: String,
+    files: Vec<FileDetails>,
+    /// Booleans, or `0` and `1` from older daemons.
+    #[serde(default)]
+    wanted: Vec<Value>,
+    #[serde(default)]
+    pieces: String,
+    piece_size: u64,
+    have_valid: u64,
+    size_when_done: u64,
+    rate_download: u64,
+    peers_connected: usize,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FileDetails {
+    name: String,
+    length: u64,
+    bytes_completed: u64,
+}
+
+impl FileDetails {
+    fn into_torrent_file(self, index: usize, downloaded: Option<u64>) -> Option<TorrentFile> {
+        let path = PathBuf::from(self.name);
+        let name = path.file_name()?.to_string_lossy().to_string();
+
+        Some(TorrentFile {
+            index,
+            name,
+            path,
+            size: self.length,
+            downloaded,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SessionStats {
+    active_torrent_count: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::available_len;
+
+    #[test]
+    fn counts_consecutive_downloaded_pieces() {
+        // Pieces 0, 1 and 3 of 10-byte pieces.
+        let pieces = [0b1101_0000];
+
+        assert_eq!(available_len(&pieces, 10, 0, 40), 20);
+        assert_eq!(available_len(&pieces, 10, 5, 40), 15);
+        assert_eq!(available_len(&pieces, 10, 5, 12), 7);
+        assert_eq!(available_len(&pieces, 10, 25, 40), 0);
+        assert_eq!(available_len(&pieces, 10, 30, 35), 5);
+        assert_eq!(available_len(&pieces, 10, 80, 90), 0);
+    }
+}
@@ -0,0 +1,139 @@
+//! Transcoding of video streams through `ffmpeg`, for codecs a device can't play.
+//!
+//! Re-encoding is expensive: a 1080p stream takes a couple of CPU cores to transcode in real
+//! time on typical hardware, lower-end devices may not keep up at all. It is only done when a
+//! request asks for it with `?transcode=<target>`, every other request is passed through.
+
+use std::{path::Path, process::Stdio, str::FromStr};
+
+use axum::{body::Body, response::Response};
+use futures_util::StreamExt;
+use http::{HeaderValue, StatusCode, Uri, header::CONTENT_TYPE};
+use tokio::{io::AsyncWriteExt, process::Command};
+use tokio_util::io::ReaderStream;
+
+use crate::error::Error;
+
+/// Formats a stream can be transcoded to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TranscodeTarget {
+    /// H.264 video and AAC audio in a fragmented MP4.
+    H264,
+}
+
+impl FromStr for TranscodeTarget {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "h264" => Ok(Self::H264),
+            _ => Err(Error::UnsupportedTranscodeTarget(s.to_owned())),
+        }
+    }
+}
+
+impl TranscodeTarget {
+    fn ffmpeg_args(self) -> &'static [&'static str] {
+        match self {
+            Self::H264 => &[
+                "-map",
+                "0:v:0",
+                "-map",
+                "0:a:0?",
+                "-c:v",
+                "libx264",
+                "-preset",
+                "veryfast",
+                "-c:a",
+                "aac",
+                "-movflags",
+                "frag_keyframe+empty_moov+default_base_moof",
+                "-f",
+                "mp4",
+            ],
+        }
+    }
+
+    fn content_type(self) -> HeaderValue {
+        match self {
+            Self::H264 => HeaderValue::from_static("video/mp4"),
+        }
+    }
+}
+
+/// Reads the `transcode` query parameter of a request, if any.
+pub(crate) fn requested_target(uri: &Uri) -> Result<Option<TranscodeTarget>, Error> {
+    uri.query()
+        .into_iter()
+        .flat_map(|query| query.split('&'))
+        .find_map(|pair| pair.strip_prefix("transcode="))
+        .map(str::parse)
+        .transpose()
+}
+
+/// Pipes `body` through `ffmpeg`, streaming its output as it is produced.
+///
+/// The output can't be seeked, so the response has neither a length nor range support.
+pub(crate) fn transcode(
+    body: Body,
+    target: TranscodeTarget,
+    ffmpeg: &Path,
+) -> Result<Response, Error> {
+    let mut child = Command::new(ffmpeg)
+        .args(["-hide_banner", "-loglevel", "error", "-i", "pipe:0"])
+        .args(target.ffmpeg_args())
+        .arg("pipe:1")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(Error::Transcode)?;
+
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let stdout = child.stdout.take().expect("stdout is piped");
+
+    // The child is owned by the feeding task: once the client goes away ffmpeg fails writing
+    // its output, the task stops and dropping the child kills what is left of it.
+    tokio::spawn(async move {
+        let mut input = body.into_data_stream();
+        while let Some(Ok(chunk)) = input.next().await {
+            if stdin.write_all(&chunk).await.is_err() {
+                break;
+            }
+        }
+        drop(stdin);
+
+        if let Err(err) = child.wait().await {
+            tracing::warn!("ffmpeg did not exit cleanly: {err}");
+        }
+    });
+
+    let mut response = Response::new(Body::from_stream(ReaderStream::with_capacity(
+        stdout,
+        64 * 1024,
+    )));
+    *response.status_mut() = StatusCode::OK;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, target.content_type());
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TranscodeTarget, requested_target};
+
+    #[test]
+    fn reads_target_from_query() {
+        let uri = "/video/abc?foo=1&transcode=h264".parse().unwrap();
+        assert_eq!(requested_target(&uri).unwrap(), Some(TranscodeTarget::H264));
+
+        let uri = "/video/abc".parse().unwrap();
+        assert_eq!(requested_target(&uri).unwrap(), None);
+
+        let uri = "/video/abc?transcode=vp9".parse().unwrap();
+        assert!(requested_target(&uri).is_err());
+    }
+}
@@ -1,10 +1,21 @@
+#[cfg(any(feature = "imaging", feature = "torrent-transmission"))]
+use std::ops::Range;
+use std::{collections::HashMap, fmt, net::Ipv4Addr};
+
+use base64::{Engine, prelude::BASE64_STANDARD};
 use bytes::Bytes;
 use http::{
-    HeaderMap, HeaderName,
-    header::{CONNECTION, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION, TE, TRANSFER_ENCODING, UPGRADE},
+    HeaderMap, HeaderName, HeaderValue,
+    header::{
+        AUTHORIZATION, CONNECTION, CONTENT_TYPE, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION, TE,
+        TRANSFER_ENCODING, UPGRADE,
+    },
 };
-use reqwest::Client;
-use url::Url;
+use mime::Mime;
+use reqwest::{Client, redirect};
+use url::{Host, Url};
+
+pub const DEFAULT_MAX_REDIRECTS: usize = 5;
 
 const HOP_BY_HOP_HEADERS: [HeaderName; 8] = [
     CONNECTION,
@@ -45,6 +56,123 @@ impl HopByHopHeadersExt for HeaderMap {
     }
 }
 
+pub trait ContentTypeExt {
+    /// Sets `Content-Type` to `mime` unless a specific type is already present.
+    fn fallback_content_type(&mut self, mime: Option<&Mime>);
+}
+
+impl ContentTypeExt for HeaderMap {
+    fn fallback_content_type(&mut self, mime: Option<&Mime>) {
+        let declares_type = self
+            .get(CONTENT_TYPE)
+            .is_some_and(|v| v != mime::APPLICATION_OCTET_STREAM.as_ref());
+
+        if !declares_type
+            && let Some(mime) = mime
+            && let Ok(value) = HeaderValue::from_str(mime.as_ref())
+        {
+            self.insert(CONTENT_TYPE, value);
+        }
+    }
+}
+
+/// Parses a single `bytes=` range against a body of `total_len` bytes.
+///
+/// Returns `None` when the range is malformed or not satisfiable.
+#[cfg(any(feature = "imaging", feature = "torrent-transmission"))]
+pub(crate) fn parse_byte_range(value: &str, total_len: u64) -> Option<Range<u64>> {
+    let (start, end) = value.trim().strip_prefix("bytes=")?.split_once('-')?;
+
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => (total_len.saturating_sub(suffix.parse().ok()?), total_len),
+        (start, "") => (start.parse().ok()?, total_len),
+        (start, end) => {
+            let end = end.parse::<u64>().ok()?.saturating_add(1);
+            (start.parse().ok()?, end.min(total_len))
+        }
+    };
+
+    (start < end).then_some(start..end)
+}
+
+/// Whether an `If-None-Match` header lists `etag`, compared weakly as it only guards `GET`s.
+pub(crate) fn etag_matches(if_none_match: &HeaderValue, etag: &str) -> bool {
+    let Ok(value) = if_none_match.to_str() else {
+        return false;
+    };
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+
+    let etag = opaque(etag);
+    value
+        .split(',')
+        .any(|tag| tag.trim() == "*" || opaque(tag) == etag)
+}
+
+/// Credentials sent to a host on outbound requests.
+#[derive(Clone)]
+pub enum Credential {
+    Basic {
+        username: String,
+        password: Option<String>,
+    },
+    Bearer(String),
+}
+
+// Keep credentials out of logs.
+impl fmt::Debug for Credential {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Credential::Basic { username, .. } => f
+                .debug_struct("Basic")
+                .field("username", username)
+                .finish_non_exhaustive(),
+            Credential::Bearer(_) => f.write_str("Bearer(..)"),
+        }
+    }
+}
+
+impl Credential {
+    fn header_value(&self) -> Option<HeaderValue> {
+        let value = match self {
+            Credential::Basic { username, password } => {
+                let pair = format!("{username}:{}", password.as_deref().unwrap_or_default());
+                format!("Basic {}", BASE64_STANDARD.encode(pair))
+            }
+            Credential::Bearer(token) => format!("Bearer {token}"),
+        };
+
+        let mut value = HeaderValue::from_str(&value).ok()?;
+        value.set_sensitive(true);
+        Some(value)
+    }
+}
+
+pub trait HostCredentialsExt {
+    /// Adds the credentials configured for the request's host, unless the request already
+    /// carries an `Authorization` header. Hosts are matched in lowercase.
+    fn apply_host_credentials(&mut self, credentials: &HashMap<String, Credential>);
+}
+
+impl HostCredentialsExt for http::Request<Option<Bytes>> {
+    fn apply_host_credentials(&mut self, credentials: &HashMap<String, Credential>) {
+        if credentials.is_empty() || self.headers().contains_key(AUTHORIZATION) {
+            return;
+        }
+
+        let Some(credential) = self
+            .uri()
+            .host()
+            .and_then(|host| credentials.get(&host.to_ascii_lowercase()))
+        else {
+            return;
+        };
+
+        if let Some(value) = credential.header_value() {
+            self.headers_mut().insert(AUTHORIZATION, value);
+        }
+    }
+}
+
 pub trait IntoReqwestRequest {
     fn into_reqwest_request(self, client: Client) -> Result<reqwest::Request, reqwest::Error>;
 }
@@ -68,3 +196,124 @@ impl IntoReqwestRequest for http::Request<Option<Bytes>> {
         }
     }
 }
+
+/// Returns a redirect policy for the proxy's [`Client`] that follows at most `max_redirects`
+/// hops and refuses any hop pointing to a loopback, private or link-local address.
+///
+/// The check runs on every redirect, so an allowed public URL can't bounce the proxy into the
+/// local network. Hostnames are not resolved, only literal IPs and `localhost` are rejected.
+pub fn redirect_policy(max_redirects: usize) -> redirect::Policy {
+    redirect::Policy::custom(move |attempt| {
+        // The first of the previous URLs is the original request, not a redirect.
+        let followed = attempt.previous().len().saturating_sub(1);
+        if followed >= max_redirects {
+            return attempt.error(format!("exceeded the maximum of {max_redirects} redirects"));
+        }
+
+        if attempt
+            .url()
+            .host()
+            .is_some_and(|host| is_private_host(&host))
+        {
+            let err = format!("refusing to redirect to private address {}", attempt.url());
+            return attempt.error(err);
+        }
+
+        attempt.follow()
+    })
+}
+
+fn is_private_host(host: &Host<&str>) -> bool {
+    match host {
+        Host::Domain(domain) => domain.eq_ignore_ascii_case("localhost"),
+        Host::Ipv4(ip) => is_private_ipv4(ip),
+        // IPv4-mapped addresses, e.g. `::ffff:127.0.0.1`, reach the IPv4 host they embed.
+        Host::Ipv6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_private_ipv4(&ip),
+            None => {
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local()
+            }
+        },
+    }
+}
+
+fn is_private_ipv4(ip: &Ipv4Addr) -> bool {
+    ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use axum::{
+        Router,
+        extract::Path,
+        response::{IntoResponse, Redirect, Response},
+        routing::get,
+    };
+    use tokio::net::TcpListener;
+    use url::Host;
+
+    use super::{is_private_host, redirect_policy};
+
+    #[test]
+    fn ipv4_mapped_addresses_are_checked_as_ipv4() {
+        let ipv6 = |ip: &str| Host::<&str>::Ipv6(ip.parse().unwrap());
+
+        assert!(is_private_host(&ipv6("::ffff:127.0.0.1")));
+        assert!(is_private_host(&ipv6("::ffff:10.0.0.1")));
+        assert!(is_private_host(&ipv6("::ffff:192.168.1.1")));
+        assert!(is_private_host(&ipv6("::ffff:169.254.169.254")));
+        assert!(!is_private_host(&ipv6("::ffff:93.184.216.34")));
+        assert!(is_private_host(&ipv6("::1")));
+        assert!(!is_private_host(&ipv6("2606:2800:220:1::1")));
+    }
+
+    /// Serves `/hop/{n}`, redirecting through `n` hops before answering, and `/private`,
+    /// redirecting to a loopback address.
+    async fn redirecting_server() -> SocketAddr {
+        async fn hop(Path(n): Path<u32>) -> Response {
+            match n {
+                0 => "done".into_response(),
+                n => Redirect::temporary(&format!("/hop/{}", n - 1)).into_response(),
+            }
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/hop/{n}", get(hop)).route(
+            "/private",
+            get(move || async move { Redirect::temporary(&format!("http://{addr}/hop/0")) }),
+        );
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        addr
+    }
+
+    #[tokio::test]
+    async fn redirects_are_followed_up_to_the_limit() {
+        let addr = redirecting_server().await;
+        // A hostname, since literal loopback addresses are refused.
+        let client = reqwest::Client::builder()
+            .redirect(redirect_policy(2))
+            .resolve("upstream.test", addr)
+            .build()
+            .unwrap();
+        let url = |path: &str| format!("http://upstream.test:{}{path}", addr.port());
+
+        let response = client.get(url("/hop/2")).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "done");
+
+        let err = client.get(url("/hop/3")).send().await.unwrap_err();
+        assert!(err.is_redirect());
+
+        let err = client.get(url("/private")).send().await.unwrap_err();
+        assert!(err.is_redirect());
+    }
+}
@@ -1,4 +1,5 @@
 use anyhow::Result;
+use tracing::{Level, level_filters::LevelFilter};
 use wasmtime::component::HasData;
 
 pub use self::generated::wasi::*;
@@ -10,10 +11,34 @@ mod generated {
     });
 }
 
-pub struct WasiLogging;
+/// Routes guest logs into `tracing`, dropping those below `min_level`.
+pub struct WasiLogging {
+    min_level: LevelFilter,
+}
+
+impl WasiLogging {
+    pub fn new(min_level: LevelFilter) -> Self {
+        Self { min_level }
+    }
+}
+
+pub trait WasiLoggingView {
+    fn logging(&mut self) -> WasiLogging;
+}
 
 impl logging::logging::Host for WasiLogging {
     fn log(&mut self, level: logging::logging::Level, context: String, message: String) {
+        let tracing_level = match level {
+            logging::logging::Level::Trace => Level::TRACE,
+            logging::logging::Level::Debug => Level::DEBUG,
+            logging::logging::Level::Info => Level::INFO,
+            logging::logging::Level::Warn => Level::WARN,
+            logging::logging::Level::Error | logging::logging::Level::Critical => Level::ERROR,
+        };
+        if tracing_level > self.min_level {
+            return;
+        }
+
         match level {
             logging::logging::Level::Trace => tracing::trace!(context = %context, "{message}"),
             logging::logging::Level::Debug => tracing::debug!(context = %context, "{message}"),
@@ -26,8 +51,10 @@ impl logging::logging::Host for WasiLogging {
     }
 }
 
-pub fn add_to_linker<T>(l: &mut wasmtime::component::Linker<T>) -> Result<()> {
-    logging::logging::add_to_linker::<T, HasWasiLogging>(l, |_| WasiLogging)
+pub fn add_to_linker<T: WasiLoggingView + Send>(
+    l: &mut wasmtime::component::Linker<T>,
+) -> Result<()> {
+    logging::logging::add_to_linker::<T, HasWasiLogging>(l, T::logging)
 }
 
 struct HasWasiLogging;